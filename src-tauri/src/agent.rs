@@ -0,0 +1,262 @@
+use libssh2_sys as raw;
+use ssh2::Session;
+use std::ffi::{c_char, CStr, CString};
+use std::io::{ErrorKind, Read, Write};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::{thread, time};
+
+use super::incoming::{self, RawChannel, CALLBACK_AUTHAGENT};
+
+const WAIT_MS: u64 = 20;
+
+// like openssh AGENT_MAX_LEN
+const MAX_MESSAGE: usize = 256 * 1024;
+
+#[cfg(windows)]
+const AGENT_PIPE: &str = r"\\.\pipe\openssh-ssh-agent";
+
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+extern "C" {
+    fn libssh2_agent_set_identity_path(agent: *mut raw::LIBSSH2_AGENT, path: *const c_char);
+}
+
+// the local agent used to authenticate, the ssh2 crate agent always takes
+// SSH_AUTH_SOCK or pageant, this one can be given the socket path
+pub struct LocalAgent {
+    session: Session,
+    raw: *mut raw::LIBSSH2_AGENT,
+}
+
+impl LocalAgent {
+    pub fn new(session: &Session, path: Option<&str>) -> Result<LocalAgent, String> {
+        let path = match path.map(CString::new).transpose() {
+            Err(_) => return Err("Invalid agent path".to_string()),
+            Ok(o) => o,
+        };
+        let mut session_raw = session.raw();
+        let agent = unsafe { raw::libssh2_agent_init(&mut *session_raw) };
+        drop(session_raw);
+        if agent.is_null() {
+            return Err("Cannot create ssh agent".to_string());
+        }
+        if let Some(path) = path {
+            // libssh2 keeps a copy
+            unsafe { libssh2_agent_set_identity_path(agent, path.as_ptr()) };
+        }
+        Ok(LocalAgent {
+            session: session.clone(),
+            raw: agent,
+        })
+    }
+    fn check(&self, rc: i32, what: &str) -> Result<(), String> {
+        if rc < 0 {
+            let e = ssh2::Error::from_session_error(&self.session, rc);
+            return Err(format!("{what}: {e}"));
+        }
+        Ok(())
+    }
+    pub fn connect(&mut self) -> Result<(), String> {
+        let rc = {
+            let _session = self.session.raw();
+            unsafe { raw::libssh2_agent_connect(self.raw) }
+        };
+        self.check(rc, "Cannot connect to ssh agent")?;
+        let rc = {
+            let _session = self.session.raw();
+            unsafe { raw::libssh2_agent_list_identities(self.raw) }
+        };
+        self.check(rc, "Cannot list ssh agent identities")
+    }
+    // the identities of the agent in its order, None past the last one
+    fn identity(&self, index: usize) -> Result<Option<*mut raw::libssh2_agent_publickey>, String> {
+        let mut prev = null_mut();
+        for _ in 0..=index {
+            let mut next = null_mut();
+            let rc = {
+                let _session = self.session.raw();
+                unsafe { raw::libssh2_agent_get_identity(self.raw, &mut next, prev) }
+            };
+            if rc == 1 {
+                return Ok(None);
+            }
+            self.check(rc, "Cannot list ssh agent identities")?;
+            prev = next;
+        }
+        Ok(Some(prev))
+    }
+    pub fn comments(&self) -> Result<Vec<String>, String> {
+        let mut comments = Vec::new();
+        while let Some(identity) = self.identity(comments.len())? {
+            let comment = unsafe { (*identity).comment };
+            if comment.is_null() {
+                comments.push(String::new());
+            } else {
+                let comment = unsafe { CStr::from_ptr(comment) };
+                comments.push(comment.to_string_lossy().to_string());
+            }
+        }
+        Ok(comments)
+    }
+    pub fn userauth(&self, user: &str, index: usize) -> Result<(), String> {
+        let user = CString::new(user).map_err(|e| e.to_string())?;
+        let identity = match self.identity(index)? {
+            None => return Err(format!("No agent identity {index}")),
+            Some(o) => o,
+        };
+        let rc = {
+            let _session = self.session.raw();
+            unsafe { raw::libssh2_agent_userauth(self.raw, user.as_ptr(), identity) }
+        };
+        self.check(rc, "Agent authentication error")
+    }
+}
+
+impl Drop for LocalAgent {
+    fn drop(&mut self) {
+        let _session = self.session.raw();
+        unsafe {
+            raw::libssh2_agent_disconnect(self.raw);
+            raw::libssh2_agent_free(self.raw);
+        }
+    }
+}
+
+// ssh -A, agent channels the server opens are relayed to the local agent
+pub struct AgentForward {
+    stop: Arc<AtomicBool>,
+}
+
+impl AgentForward {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+// path is the local agent socket, SSH_AUTH_SOCK or the openssh pipe when None
+pub fn start(session: &Session, path: Option<String>) -> Result<AgentForward, String> {
+    // fail now rather than on the first remote request
+    connect(path.as_deref())?;
+    incoming::listen(session, CALLBACK_AUTHAGENT);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let agent = AgentForward { stop: stop.clone() };
+    let session = session.clone();
+    thread::spawn(move || {
+        while !stop.load(Ordering::SeqCst) {
+            for channel in incoming::accept(&session, CALLBACK_AUTHAGENT) {
+                let stop = stop.clone();
+                let path = path.clone();
+                thread::spawn(move || {
+                    if let Err(e) = relay(channel, path.as_deref(), &stop) {
+                        println!("agent connection closed: {e}");
+                    }
+                });
+            }
+            thread::sleep(time::Duration::from_millis(WAIT_MS));
+        }
+        println!("agent forwarding stopped");
+    });
+    Ok(agent)
+}
+
+// the agent protocol is request and reply, so a blocking local stream
+// is enough and windows named pipes work the same as unix sockets
+fn relay(channel: RawChannel, path: Option<&str>, stop: &AtomicBool) -> Result<(), String> {
+    let mut local = connect(path)?;
+    while let Some(request) = read_message(&channel, stop)? {
+        local.write_all(&request).map_err(|e| e.to_string())?;
+        let mut header = [0; 4];
+        local.read_exact(&mut header).map_err(|e| e.to_string())?;
+        let mut reply = header.to_vec();
+        reply.resize(4 + message_len(&header)?, 0);
+        local
+            .read_exact(&mut reply[4..])
+            .map_err(|e| e.to_string())?;
+        channel.write_all(&reply).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn connect(path: Option<&str>) -> Result<Box<dyn Stream>, String> {
+    let path = match path {
+        Some(o) => o.to_string(),
+        None => match std::env::var("SSH_AUTH_SOCK") {
+            Err(_) => return Err("Agent forwarding: SSH_AUTH_SOCK is not set".to_string()),
+            Ok(o) => o,
+        },
+    };
+    let stream = std::os::unix::net::UnixStream::connect(&path)
+        .map_err(|e| format!("Cannot connect to agent {path}: {e}"))?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(windows)]
+fn connect(path: Option<&str>) -> Result<Box<dyn Stream>, String> {
+    let path = path.unwrap_or(AGENT_PIPE);
+    let pipe = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|e| format!("Cannot connect to agent {path}: {e}"))?;
+    Ok(Box::new(pipe))
+}
+
+// a whole request with its length, None when the channel closes between requests
+fn read_message(channel: &RawChannel, stop: &AtomicBool) -> Result<Option<Vec<u8>>, String> {
+    let mut message = vec![0; 4];
+    if !read_exact(channel, &mut message, stop)? {
+        return Ok(None);
+    }
+    let len = message_len(&message)?;
+    message.resize(4 + len, 0);
+    if !read_exact(channel, &mut message[4..], stop)? {
+        return Err("Agent request truncated".to_string());
+    }
+    Ok(Some(message))
+}
+
+// false when the channel closes or stop is set before buf is filled
+fn read_exact(channel: &RawChannel, buf: &mut [u8], stop: &AtomicBool) -> Result<bool, String> {
+    let mut done = 0;
+    while done < buf.len() {
+        if stop.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        match channel.read(&mut buf[done..]) {
+            Ok(0) if channel.eof() => return Ok(false),
+            Ok(0) => thread::sleep(time::Duration::from_millis(WAIT_MS)),
+            Ok(n) => done += n,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(time::Duration::from_millis(WAIT_MS));
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    Ok(true)
+}
+
+fn message_len(header: &[u8]) -> Result<usize, String> {
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if len == 0 || len > MAX_MESSAGE {
+        return Err(format!("Invalid agent message length {len}"));
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_length() {
+        assert_eq!(message_len(&[0, 0, 0, 1]), Ok(1));
+        assert_eq!(message_len(&[0, 0, 1, 0]), Ok(256));
+        assert!(message_len(&[0, 0, 0, 0]).is_err());
+        assert!(message_len(&[0, 4, 0, 1]).is_err());
+    }
+}
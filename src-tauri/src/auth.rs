@@ -0,0 +1,147 @@
+use ssh2::{KeyboardInteractivePrompt, Prompt};
+
+// asks the user to answer a keyboard-interactive challenge, None cancels
+pub type ChallengePrompt = dyn Fn(&Challenge) -> Option<Vec<String>> + Send;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Challenge {
+    pub username: String,
+    pub instructions: String,
+    pub prompts: Vec<ChallengeField>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ChallengeField {
+    pub text: String,
+    pub echo: bool,
+}
+
+// how a connection was authenticated, reported to the frontend
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "method", rename_all = "kebab-case")]
+pub enum AuthMethod {
+    None,
+    Agent,
+    PublicKey { key: String },
+    Password,
+    KeyboardInteractive,
+}
+
+// bridges libssh2 keyboard-interactive rounds to the user prompt
+pub struct Prompter<'a> {
+    prompt: Option<&'a ChallengePrompt>,
+    password: Option<&'a str>,
+    pub cancelled: bool,
+}
+
+impl<'a> Prompter<'a> {
+    pub fn new(prompt: Option<&'a ChallengePrompt>, password: Option<&'a str>) -> Self {
+        Self {
+            prompt,
+            password,
+            cancelled: false,
+        }
+    }
+}
+
+impl KeyboardInteractivePrompt for Prompter<'_> {
+    fn prompt<'b>(
+        &mut self,
+        username: &str,
+        instructions: &str,
+        prompts: &[Prompt<'b>],
+    ) -> Vec<String> {
+        // informational rounds, duo push notices for example
+        if prompts.is_empty() {
+            if !instructions.is_empty() {
+                println!("keyboard-interactive: {instructions}");
+            }
+            return vec![];
+        }
+
+        // a lone password prompt is answered once with the password we already have
+        let is_password = prompts.len() == 1
+            && !prompts[0].echo
+            && prompts[0].text.to_lowercase().contains("password");
+        if is_password {
+            if let Some(password) = self.password.take() {
+                return vec![password.to_string()];
+            }
+        }
+
+        let challenge = Challenge {
+            username: username.to_string(),
+            instructions: instructions.to_string(),
+            prompts: prompts
+                .iter()
+                .map(|p| ChallengeField {
+                    text: p.text.to_string(),
+                    echo: p.echo,
+                })
+                .collect(),
+        };
+        let responses = match self.prompt {
+            None => None,
+            Some(prompt) => prompt(&challenge),
+        };
+        match responses {
+            Some(responses) => responses,
+            None => {
+                self.cancelled = true;
+                vec![String::new(); prompts.len()]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    fn prompt(text: &str, echo: bool) -> Prompt<'_> {
+        Prompt {
+            text: Cow::Borrowed(text),
+            echo,
+        }
+    }
+
+    #[test]
+    fn password_answered_once() {
+        let otp = |c: &Challenge| Some(vec![format!("otp for {}", c.prompts[0].text)]);
+        let mut prompter = Prompter::new(Some(&otp), Some("secret"));
+        let r = prompter.prompt("user", "", &[prompt("Password: ", false)]);
+        assert_eq!(r, vec!["secret"]);
+        let r = prompter.prompt("user", "", &[prompt("Password: ", false)]);
+        assert_eq!(r, vec!["otp for Password: "]);
+        assert!(!prompter.cancelled);
+    }
+
+    #[test]
+    fn challenge_forwarded() {
+        let otp = |c: &Challenge| {
+            assert_eq!(c.instructions, "Duo two-factor login");
+            assert_eq!(c.prompts.len(), 2);
+            assert!(c.prompts[1].echo);
+            Some(vec!["a".to_string(), "b".to_string()])
+        };
+        let mut prompter = Prompter::new(Some(&otp), Some("secret"));
+        let prompts = [prompt("Passcode: ", false), prompt("Option: ", true)];
+        let r = prompter.prompt("user", "Duo two-factor login", &prompts);
+        assert_eq!(r, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn cancelled() {
+        let cancel = |_: &Challenge| None;
+        let mut prompter = Prompter::new(Some(&cancel), None);
+        let r = prompter.prompt("user", "", &[prompt("Verification code: ", true)]);
+        assert_eq!(r, vec![""]);
+        assert!(prompter.cancelled);
+
+        let mut prompter = Prompter::new(None, None);
+        prompter.prompt("user", "", &[prompt("Verification code: ", true)]);
+        assert!(prompter.cancelled);
+        assert!(prompter.prompt("user", "Push sent", &[]).is_empty());
+    }
+}
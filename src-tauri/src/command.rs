@@ -1,8 +1,17 @@
-use std::process::Command;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::process::{Command, Stdio};
+use std::thread;
 
 #[cfg(target_os = "windows")]
 use std::os::windows::process::CommandExt;
 
+use super::tunnel;
+
+// do not open a console window for proxy commands
+#[cfg(target_os = "windows")]
+const CREATE_NO_WINDOW: u32 = 0x08000000;
+
 pub fn run(cmd: &str) -> (String, String, i32) {
     #[cfg(target_os = "windows")]
     let r = Command::new("cmd").arg("/c").raw_arg(cmd).output().unwrap();
@@ -14,6 +23,101 @@ pub fn run(cmd: &str) -> (String, String, i32) {
     (stdout, stderr, r.status.code().unwrap())
 }
 
+// openssh ProxyCommand tokens, %h host, %p port and %% for a literal %
+pub fn expand_proxy_command(cmd: &str, host: &str, port: u16) -> String {
+    let mut expanded = String::new();
+    let mut chars = cmd.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('h') => expanded.push_str(host),
+            Some('p') => expanded.push_str(&port.to_string()),
+            Some('%') => expanded.push('%'),
+            Some(o) => {
+                expanded.push('%');
+                expanded.push(o);
+            }
+            None => expanded.push('%'),
+        }
+    }
+    expanded
+}
+
+// single quote a word for a posix shell, the remote login shells
+pub fn quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+// run a proxy command and return a socket bridged to its stdin and stdout,
+// ssh sessions and the mio reader need a real socket, not pipes
+pub fn proxy(cmd: &str) -> Result<TcpStream, String> {
+    #[cfg(target_os = "windows")]
+    let mut command = Command::new("cmd");
+    #[cfg(target_os = "windows")]
+    command
+        .arg("/c")
+        .raw_arg(cmd)
+        .creation_flags(CREATE_NO_WINDOW);
+    #[cfg(not(target_os = "windows"))]
+    let mut command = Command::new("sh");
+    #[cfg(not(target_os = "windows"))]
+    command.arg("-c").arg(cmd);
+
+    let mut child = match command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+    {
+        Err(e) => return Err(format!("Cannot run proxy command {cmd}: {e}")),
+        Ok(o) => o,
+    };
+    let (local, remote) = match tunnel::local_pair() {
+        Err(e) => return Err(format!("Cannot create proxy socket: {e}")),
+        Ok(o) => o,
+    };
+    let (mut stdin, mut stdout) = match (child.stdin.take(), child.stdout.take()) {
+        (Some(stdin), Some(stdout)) => (stdin, stdout),
+        _ => return Err(format!("Cannot open proxy command pipes: {cmd}")),
+    };
+    let (mut reader, mut writer) = match remote.try_clone() {
+        Err(e) => return Err(format!("Cannot clone proxy socket: {e}")),
+        Ok(o) => (remote, o),
+    };
+
+    // command output -> session
+    thread::spawn(move || {
+        let _ = std::io::copy(&mut stdout, &mut writer);
+        let _ = writer.shutdown(Shutdown::Write);
+    });
+    // session -> command input, the command is stopped when the session closes
+    let name = cmd.to_string();
+    thread::spawn(move || {
+        let mut buf = vec![0; 32 * 1024];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if stdin.write_all(&buf[..n]).is_err() || stdin.flush().is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        drop(stdin);
+        let _ = reader.shutdown(Shutdown::Both);
+        let _ = child.kill();
+        match child.wait() {
+            Err(e) => println!("proxy command {name} error: {e}"),
+            Ok(status) => println!("proxy command {name} exited: {status}"),
+        }
+    });
+    Ok(local)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -68,4 +172,29 @@ mod tests {
             //assert_eq!(r, 2);
         }
     }
+
+    #[test]
+    fn proxy_command_tokens() {
+        let cmd = expand_proxy_command("nc -X connect -x proxy:3128 %h %p 100%% %r", "db1", 2222);
+        assert_eq!(cmd, "nc -X connect -x proxy:3128 db1 2222 100% %r");
+    }
+    #[test]
+    fn quote_words() {
+        assert_eq!(quote("/srv/app"), "'/srv/app'");
+        assert_eq!(quote("it's $HOME"), "'it'\\''s $HOME'");
+        assert_eq!(quote(""), "''");
+    }
+    #[test]
+    fn proxy_command_bridge() {
+        let cmd = if cfg!(windows) {
+            "findstr \"^\""
+        } else {
+            "cat"
+        };
+        let mut tcp = proxy(cmd).unwrap();
+        tcp.write_all(b"hello\n").unwrap();
+        let mut buf = [0; 6];
+        tcp.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello\n");
+    }
 }
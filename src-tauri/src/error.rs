@@ -0,0 +1,116 @@
+use std::fmt;
+
+use super::known_hosts::HostKeyError;
+
+// errors of the ssh commands, the frontend gets { kind, message }
+// and can react to the kind, asking for a passphrase for example
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "kind", content = "message", rename_all = "kebab-case")]
+pub enum SshError {
+    // resolving, tcp, proxy commands, jump hosts and the handshake
+    Connect(String),
+    // the host key was not trusted
+    HostKey(String),
+    // the host key differs from known_hosts, maybe an attack
+    HostKeyChanged(String),
+    Auth(String),
+    // the private key is encrypted and no passphrase was given
+    PassphraseRequired(String),
+    Channel(String),
+    Sftp(String),
+    Io(String),
+    // stopped by the user, a transfer for example
+    Cancelled(String),
+}
+
+impl SshError {
+    pub fn message(&self) -> &str {
+        match self {
+            SshError::Connect(m)
+            | SshError::HostKey(m)
+            | SshError::HostKeyChanged(m)
+            | SshError::Auth(m)
+            | SshError::PassphraseRequired(m)
+            | SshError::Channel(m)
+            | SshError::Sftp(m)
+            | SshError::Io(m)
+            | SshError::Cancelled(m) => m,
+        }
+    }
+    // the same kind with a changed message, to say where it happened
+    pub fn map_message(self, f: impl FnOnce(&str) -> String) -> Self {
+        match self {
+            SshError::Connect(m) => SshError::Connect(f(&m)),
+            SshError::HostKey(m) => SshError::HostKey(f(&m)),
+            SshError::HostKeyChanged(m) => SshError::HostKeyChanged(f(&m)),
+            SshError::Auth(m) => SshError::Auth(f(&m)),
+            SshError::PassphraseRequired(m) => SshError::PassphraseRequired(f(&m)),
+            SshError::Channel(m) => SshError::Channel(f(&m)),
+            SshError::Sftp(m) => SshError::Sftp(f(&m)),
+            SshError::Io(m) => SshError::Io(f(&m)),
+            SshError::Cancelled(m) => SshError::Cancelled(f(&m)),
+        }
+    }
+}
+
+impl fmt::Display for SshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for SshError {}
+
+impl From<HostKeyError> for SshError {
+    fn from(e: HostKeyError) -> Self {
+        match e {
+            HostKeyError::Changed(_) => SshError::HostKeyChanged(e.to_string()),
+            _ => SshError::HostKey(e.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for SshError {
+    fn from(e: std::io::Error) -> Self {
+        SshError::Io(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialized_kind() {
+        let e = SshError::PassphraseRequired("Passphrase required: id_rsa".to_string());
+        let json = serde_json::to_string(&e).unwrap();
+        assert_eq!(
+            json,
+            r#"{"kind":"passphrase-required","message":"Passphrase required: id_rsa"}"#
+        );
+        let json = serde_json::to_string(&SshError::HostKey("changed".to_string())).unwrap();
+        assert_eq!(json, r#"{"kind":"host-key","message":"changed"}"#);
+    }
+
+    #[test]
+    fn changed_host_key() {
+        use crate::known_hosts::HostKey;
+        let key = HostKey {
+            host: "example.com".to_string(),
+            port: 22,
+            key_type: "ssh-ed25519".to_string(),
+            fingerprint: "SHA256:abc".to_string(),
+        };
+        let e = SshError::from(HostKeyError::Changed(key));
+        assert!(matches!(e, SshError::HostKeyChanged(_)));
+        let json = serde_json::to_string(&e).unwrap();
+        assert!(json.starts_with(r#"{"kind":"host-key-changed","#));
+    }
+
+    #[test]
+    fn message_context() {
+        let e = SshError::Auth("denied".to_string()).map_message(|e| format!("Jump server 1 {e}"));
+        assert_eq!(e, SshError::Auth("Jump server 1 denied".to_string()));
+        assert_eq!(e.to_string(), "Jump server 1 denied");
+    }
+}
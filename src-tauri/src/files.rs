@@ -0,0 +1,113 @@
+use ssh2::{FileStat, FileType};
+use std::path::Path;
+
+// a remote file of the file browser, mode has only the permission bits,
+// mtime is in seconds since the epoch
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct FileEntry {
+    pub name: String,
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: FileKind,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+    // where a symlink points to
+    pub target: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+impl FileEntry {
+    pub fn new(path: &Path, stat: &FileStat) -> Self {
+        let name = match path.file_name() {
+            None => path.to_string_lossy(),
+            Some(o) => o.to_string_lossy(),
+        };
+        let kind = match stat.file_type() {
+            FileType::RegularFile => FileKind::File,
+            FileType::Directory => FileKind::Dir,
+            FileType::Symlink => FileKind::Symlink,
+            _ => FileKind::Other,
+        };
+        Self {
+            name: name.to_string(),
+            path: path.to_string_lossy().to_string(),
+            kind,
+            size: stat.size.unwrap_or(0),
+            mode: stat.perm.unwrap_or(0) & 0o7777,
+            uid: stat.uid.unwrap_or(0),
+            gid: stat.gid.unwrap_or(0),
+            mtime: stat.mtime.unwrap_or(0),
+            target: None,
+        }
+    }
+}
+
+// directories first, then by name, without . and ..
+pub fn sort(mut entries: Vec<FileEntry>) -> Vec<FileEntry> {
+    entries.retain(|e| e.name != "." && e.name != "..");
+    entries.sort_by(|a, b| {
+        let a_dir = a.kind != FileKind::Dir;
+        let b_dir = b.kind != FileKind::Dir;
+        a_dir.cmp(&b_dir).then_with(|| a.name.cmp(&b.name))
+    });
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat(perm: u32) -> FileStat {
+        FileStat {
+            size: Some(42),
+            uid: Some(1000),
+            gid: Some(100),
+            perm: Some(perm),
+            atime: Some(1),
+            mtime: Some(1700000000),
+        }
+    }
+
+    #[test]
+    fn entry_from_stat() {
+        let e = FileEntry::new(Path::new("/home/user/notes.txt"), &stat(0o100644));
+        assert_eq!(e.name, "notes.txt");
+        assert_eq!(e.path, "/home/user/notes.txt");
+        assert_eq!(e.kind, FileKind::File);
+        assert_eq!((e.size, e.mode, e.uid, e.gid), (42, 0o644, 1000, 100));
+        assert_eq!(e.mtime, 1700000000);
+
+        assert_eq!(FileEntry::new(Path::new("/"), &stat(0o040755)).name, "/");
+        let link = FileEntry::new(Path::new("/l"), &stat(0o120777));
+        assert_eq!(link.kind, FileKind::Symlink);
+        let socket = FileEntry::new(Path::new("/s"), &stat(0o140755));
+        assert_eq!(socket.kind, FileKind::Other);
+
+        let tmp = FileEntry::new(Path::new("/tmp"), &stat(0o041777));
+        let json = serde_json::to_string(&tmp).unwrap();
+        assert!(json.contains(r#""type":"dir""#), "{json}");
+        assert!(json.contains(&format!(r#""mode":{}"#, 0o1777)), "{json}");
+    }
+
+    #[test]
+    fn dirs_first() {
+        let entries = ["b", "..", "a", "c", "."]
+            .iter()
+            .zip([0o100644, 0o040755, 0o100644, 0o040755, 0o040755])
+            .map(|(name, perm)| FileEntry::new(Path::new(name), &stat(perm)))
+            .collect();
+        let names: Vec<String> = sort(entries).into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec!["c", "a", "b"]);
+    }
+}
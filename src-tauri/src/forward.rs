@@ -0,0 +1,448 @@
+use ssh2::Session;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::{thread, time};
+
+use super::ssh::Ssh;
+use super::tunnel::{self, Traffic};
+
+const WAIT_MS: u64 = 20;
+
+// clients that do not finish the socks handshake in time are dropped
+const SOCKS_TIMEOUT_SECS: u64 = 10;
+
+// socks5 reply codes, rfc 1928
+const SOCKS_SUCCEEDED: u8 = 0x00;
+const SOCKS_FAILURE: u8 = 0x01;
+const SOCKS_REFUSED: u8 = 0x05;
+const SOCKS_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const SOCKS_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+// counters of a forward, updated by its connection threads
+#[derive(Default)]
+pub struct Stats {
+    pub connections: AtomicU64,
+    pub active: AtomicU32,
+    pub traffic: Traffic,
+}
+
+// listed by list_forwards, bind is where connections are accepted,
+// host and port where they are sent
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ForwardInfo {
+    pub id: u32,
+    pub kind: String,
+    pub bind_host: String,
+    pub bind_port: u16,
+    pub host: String,
+    pub port: u16,
+    pub connections: u64,
+    pub active: u32,
+    pub sent: u64,
+    pub received: u64,
+}
+
+pub struct Forward {
+    kind: &'static str,
+    bind_host: String,
+    bind_port: u16,
+    host: String,
+    port: u16,
+    stats: Arc<Stats>,
+}
+
+impl Forward {
+    pub fn info(&self, id: u32) -> ForwardInfo {
+        let stats = &self.stats;
+        ForwardInfo {
+            id,
+            kind: self.kind.to_string(),
+            bind_host: self.bind_host.clone(),
+            bind_port: self.bind_port,
+            host: self.host.clone(),
+            port: self.port,
+            connections: stats.connections.load(Ordering::SeqCst),
+            active: stats.active.load(Ordering::SeqCst),
+            sent: stats.traffic.sent.load(Ordering::SeqCst),
+            received: stats.traffic.received.load(Ordering::SeqCst),
+        }
+    }
+    // the listener and every open connection end within WAIT_MS
+    pub fn stop(&self) {
+        self.stats.traffic.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+// pump one connection through a channel, counted in the forward stats
+fn serve(channel: ssh2::Channel, stream: TcpStream, session_tcp: TcpStream, stats: Arc<Stats>) {
+    stats.connections.fetch_add(1, Ordering::SeqCst);
+    stats.active.fetch_add(1, Ordering::SeqCst);
+    thread::spawn(move || {
+        if let Err(e) = tunnel::pump(channel, stream, session_tcp, &stats.traffic) {
+            println!("forward connection closed: {e}");
+        }
+        stats.active.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+// ssh -L, connections accepted on bind_host:bind_port are sent to host:port
+// through direct-tcpip channels of the non-blocking session
+pub fn local(
+    session: &Session,
+    session_tcp: &TcpStream,
+    bind_host: &str,
+    bind_port: u16,
+    host: &str,
+    port: u16,
+) -> Result<Forward, String> {
+    let listener = match TcpListener::bind((bind_host, bind_port)) {
+        Err(e) => return Err(format!("Cannot listen on {bind_host}:{bind_port}: {e}")),
+        Ok(o) => o,
+    };
+    // port 0 picks a free port
+    let bind_port = match listener.local_addr() {
+        Err(e) => return Err(format!("Cannot get listener address: {e}")),
+        Ok(o) => o.port(),
+    };
+    if let Err(e) = listener.set_nonblocking(true) {
+        return Err(format!("Cannot set listener non-blocking: {e}"));
+    }
+
+    let forward = Forward {
+        kind: "local",
+        bind_host: bind_host.to_string(),
+        bind_port,
+        host: host.to_string(),
+        port,
+        stats: Arc::new(Stats::default()),
+    };
+    let session = session.clone();
+    let session_tcp = match session_tcp.try_clone() {
+        Err(e) => return Err(format!("Cannot clone tcp stream: {e}")),
+        Ok(o) => o,
+    };
+    let stats = forward.stats.clone();
+    let target = (host.to_string(), port);
+    println!("forwarding {bind_host}:{bind_port} to {host}:{port}");
+
+    thread::spawn(move || {
+        let (host, port) = target;
+        while !stats.traffic.stop.load(Ordering::SeqCst) {
+            let (stream, addr) = match listener.accept() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(time::Duration::from_millis(WAIT_MS));
+                    continue;
+                }
+                Err(e) => {
+                    println!("forward to {host}:{port} stopped: {e}");
+                    break;
+                }
+                Ok(o) => o,
+            };
+            let origin = (addr.ip().to_string(), addr.port());
+            let channel = Ssh::retry(|| {
+                session.channel_direct_tcpip(&host, port, Some((&origin.0, origin.1)))
+            });
+            let channel = match channel {
+                Err(e) => {
+                    println!("cannot forward {addr} to {host}:{port}: {e}");
+                    continue;
+                }
+                Ok(o) => o,
+            };
+            match session_tcp.try_clone() {
+                Err(e) => println!("cannot clone tcp stream: {e}"),
+                Ok(tcp) => serve(channel, stream, tcp, stats.clone()),
+            }
+        }
+    });
+    Ok(forward)
+}
+
+// ssh -R, the server listens on bind_host:bind_port and the accepted
+// forwarded-tcpip channels are connected to host:port on this side
+pub fn remote(
+    session: &Session,
+    session_tcp: &TcpStream,
+    bind_host: &str,
+    bind_port: u16,
+    host: &str,
+    port: u16,
+) -> Result<Forward, String> {
+    let listen = Ssh::retry(|| session.channel_forward_listen(bind_port, Some(bind_host), None));
+    // port 0 lets the server pick one
+    let (mut listener, bind_port) = match listen {
+        Err(e) => return Err(format!("Cannot listen on {bind_host}:{bind_port}: {e}")),
+        Ok(o) => o,
+    };
+
+    let forward = Forward {
+        kind: "remote",
+        bind_host: bind_host.to_string(),
+        bind_port,
+        host: host.to_string(),
+        port,
+        stats: Arc::new(Stats::default()),
+    };
+    let session_tcp = match session_tcp.try_clone() {
+        Err(e) => return Err(format!("Cannot clone tcp stream: {e}")),
+        Ok(o) => o,
+    };
+    let stats = forward.stats.clone();
+    let target = (host.to_string(), port);
+    println!("forwarding remote {bind_host}:{bind_port} to {host}:{port}");
+
+    // the listener is cancelled on the server when dropped with the thread
+    thread::spawn(move || {
+        let (host, port) = target;
+        while !stats.traffic.stop.load(Ordering::SeqCst) {
+            let mut channel = match listener.accept() {
+                Err(e) if e.code() == ssh2::ErrorCode::Session(-37) => {
+                    thread::sleep(time::Duration::from_millis(WAIT_MS));
+                    continue;
+                }
+                Err(e) => {
+                    println!("remote forward to {host}:{port} stopped: {e}");
+                    break;
+                }
+                Ok(o) => o,
+            };
+            let stream = match TcpStream::connect((host.as_str(), port)) {
+                Err(e) => {
+                    println!("cannot connect remote forward to {host}:{port}: {e}");
+                    let _ = Ssh::retry(|| channel.close());
+                    continue;
+                }
+                Ok(o) => o,
+            };
+            match session_tcp.try_clone() {
+                Err(e) => println!("cannot clone tcp stream: {e}"),
+                Ok(tcp) => serve(channel, stream, tcp, stats.clone()),
+            }
+        }
+    });
+    Ok(forward)
+}
+
+// ssh -D, a socks5 server on bind_host:bind_port, every CONNECT request
+// opens a direct-tcpip channel to the requested address
+pub fn dynamic(
+    session: &Session,
+    session_tcp: &TcpStream,
+    bind_host: &str,
+    bind_port: u16,
+) -> Result<Forward, String> {
+    let listener = match TcpListener::bind((bind_host, bind_port)) {
+        Err(e) => return Err(format!("Cannot listen on {bind_host}:{bind_port}: {e}")),
+        Ok(o) => o,
+    };
+    let bind_port = match listener.local_addr() {
+        Err(e) => return Err(format!("Cannot get listener address: {e}")),
+        Ok(o) => o.port(),
+    };
+    if let Err(e) = listener.set_nonblocking(true) {
+        return Err(format!("Cannot set listener non-blocking: {e}"));
+    }
+
+    let forward = Forward {
+        kind: "dynamic",
+        bind_host: bind_host.to_string(),
+        bind_port,
+        host: String::new(),
+        port: 0,
+        stats: Arc::new(Stats::default()),
+    };
+    let session = session.clone();
+    let session_tcp = match session_tcp.try_clone() {
+        Err(e) => return Err(format!("Cannot clone tcp stream: {e}")),
+        Ok(o) => o,
+    };
+    let stats = forward.stats.clone();
+    println!("socks proxy on {bind_host}:{bind_port}");
+
+    thread::spawn(move || {
+        while !stats.traffic.stop.load(Ordering::SeqCst) {
+            let stream = match listener.accept() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(time::Duration::from_millis(WAIT_MS));
+                    continue;
+                }
+                Err(e) => {
+                    println!("socks proxy stopped: {e}");
+                    break;
+                }
+                Ok((stream, _)) => stream,
+            };
+            let (session, stats) = (session.clone(), stats.clone());
+            let session_tcp = match session_tcp.try_clone() {
+                Err(e) => {
+                    println!("cannot clone tcp stream: {e}");
+                    continue;
+                }
+                Ok(o) => o,
+            };
+            // the handshake is slow, do not hold the listener
+            thread::spawn(move || {
+                if let Err(e) = socks_connect(&session, stream, session_tcp, stats) {
+                    println!("socks connection failed: {e}");
+                }
+            });
+        }
+    });
+    Ok(forward)
+}
+
+fn socks_connect(
+    session: &Session,
+    mut stream: TcpStream,
+    session_tcp: TcpStream,
+    stats: Arc<Stats>,
+) -> Result<(), String> {
+    // accepted sockets inherit non-blocking on windows
+    let timeout = Some(time::Duration::from_secs(SOCKS_TIMEOUT_SECS));
+    stream
+        .set_nonblocking(false)
+        .and_then(|_| stream.set_read_timeout(timeout))
+        .map_err(|e| e.to_string())?;
+
+    let (host, port) = socks_request(&mut stream)?;
+    let origin = stream.peer_addr().map_err(|e| e.to_string())?;
+    let origin = (origin.ip().to_string(), origin.port());
+    let channel =
+        Ssh::retry(|| session.channel_direct_tcpip(&host, port, Some((&origin.0, origin.1))));
+    let channel = match channel {
+        Err(e) => {
+            let _ = socks_reply(&mut stream, SOCKS_REFUSED);
+            return Err(format!("cannot connect to {host}:{port}: {e}"));
+        }
+        Ok(o) => o,
+    };
+    socks_reply(&mut stream, SOCKS_SUCCEEDED)?;
+    stream.set_read_timeout(None).map_err(|e| e.to_string())?;
+    serve(channel, stream, session_tcp, stats);
+    Ok(())
+}
+
+// method negotiation and request of a socks5 client, only no authentication
+// and CONNECT are supported, returns the address to connect to
+fn socks_request(stream: &mut (impl Read + Write)) -> Result<(String, u16), String> {
+    let header = read(stream, 2)?;
+    if header[0] != 5 {
+        return Err(format!("Unsupported socks version {}", header[0]));
+    }
+    let methods = read(stream, header[1] as usize)?;
+    if !methods.contains(&0) {
+        let _ = stream.write_all(&[5, 0xff]);
+        return Err("No supported socks authentication method".to_string());
+    }
+    stream.write_all(&[5, 0]).map_err(|e| e.to_string())?;
+
+    let request = read(stream, 4)?;
+    if request[0] != 5 {
+        return Err(format!("Unsupported socks version {}", request[0]));
+    }
+    let host = match request[3] {
+        1 => {
+            let a: [u8; 4] = read(stream, 4)?.try_into().unwrap();
+            Ipv4Addr::from(a).to_string()
+        }
+        3 => {
+            let len = read(stream, 1)?[0] as usize;
+            String::from_utf8_lossy(&read(stream, len)?).to_string()
+        }
+        4 => {
+            let a: [u8; 16] = read(stream, 16)?.try_into().unwrap();
+            Ipv6Addr::from(a).to_string()
+        }
+        t => {
+            let _ = socks_reply(stream, SOCKS_ADDRESS_NOT_SUPPORTED);
+            return Err(format!("Unsupported socks address type {t}"));
+        }
+    };
+    let port = read(stream, 2)?;
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    if request[1] != 1 {
+        let _ = socks_reply(stream, SOCKS_COMMAND_NOT_SUPPORTED);
+        return Err(format!("Unsupported socks command {}", request[1]));
+    }
+    if host.is_empty() {
+        let _ = socks_reply(stream, SOCKS_FAILURE);
+        return Err("Empty socks address".to_string());
+    }
+    Ok((host, port))
+}
+
+fn read(stream: &mut impl Read, n: usize) -> Result<Vec<u8>, String> {
+    let mut buf = vec![0; n];
+    stream.read_exact(&mut buf).map_err(|e| e.to_string())?;
+    Ok(buf)
+}
+
+// the bound address is not known through the channel, clients ignore it
+fn socks_reply(stream: &mut impl Write, code: u8) -> Result<(), String> {
+    stream
+        .write_all(&[5, code, 0, 1, 0, 0, 0, 0, 0, 0])
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // client bytes are written before parsing, the replies are read back after
+    fn request(client: &[u8]) -> (Result<(String, u16), String>, Vec<u8>) {
+        let (mut a, mut b) = tunnel::local_pair().unwrap();
+        a.write_all(client).unwrap();
+        a.shutdown(std::net::Shutdown::Write).unwrap();
+        let r = socks_request(&mut b);
+        drop(b);
+        let mut reply = Vec::new();
+        // unread client bytes can reset the connection
+        let _ = a.read_to_end(&mut reply);
+        (r, reply)
+    }
+
+    #[test]
+    fn socks_ipv4() {
+        let (r, reply) = request(&[5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 1, 0x1f, 0x90]);
+        assert_eq!(r.unwrap(), ("10.0.0.1".to_string(), 8080));
+        assert_eq!(reply, [5, 0]);
+    }
+
+    #[test]
+    fn socks_domain() {
+        let mut client = vec![5, 2, 2, 0, 5, 1, 0, 3, 9];
+        client.extend(b"localhost");
+        client.extend([0, 80]);
+        let (r, _) = request(&client);
+        assert_eq!(r.unwrap(), ("localhost".to_string(), 80));
+    }
+
+    #[test]
+    fn socks_ipv6() {
+        let mut client = vec![5, 1, 0, 5, 1, 0, 4];
+        client.extend(Ipv6Addr::LOCALHOST.octets());
+        client.extend([1, 187]);
+        let (r, _) = request(&client);
+        assert_eq!(r.unwrap(), ("::1".to_string(), 443));
+    }
+
+    #[test]
+    fn socks_unsupported() {
+        // username/password authentication only
+        let (r, reply) = request(&[5, 1, 2]);
+        assert!(r.is_err());
+        assert_eq!(reply, [5, 0xff]);
+
+        // BIND
+        let (r, reply) = request(&[5, 1, 0, 5, 2, 0, 1, 10, 0, 0, 1, 0, 80]);
+        assert!(r.is_err());
+        assert_eq!(reply[2..4], [5, SOCKS_COMMAND_NOT_SUPPORTED]);
+
+        // socks4
+        let (r, _) = request(&[4, 1, 0, 80, 10, 0, 0, 1, 0]);
+        assert!(r.is_err());
+    }
+}
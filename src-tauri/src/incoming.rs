@@ -0,0 +1,415 @@
+use libssh2_sys as raw;
+use ssh2::Session;
+use std::ffi::{c_char, c_int, c_uint, c_void, CString};
+use std::io::{ErrorKind, Read, Write};
+use std::ptr::{null, null_mut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::{thread, time};
+
+// channels the server opens, x11 and agent connections, are not wrapped by
+// the ssh2 crate, libssh2 hands them to callbacks and they are used raw here,
+// like the terminals, which need requests the ssh2 crate does not have
+
+const WAIT_MS: u64 = 20;
+
+// libssh2_session_callback_set types
+pub const CALLBACK_X11: c_int = 4;
+pub const CALLBACK_AUTHAGENT: c_int = 7;
+
+extern "C" {
+    fn libssh2_session_callback_set(
+        session: *mut raw::LIBSSH2_SESSION,
+        cbtype: c_int,
+        callback: *mut c_void,
+    ) -> *mut c_void;
+}
+
+// session, callback type and channel pointers, the callbacks run inside
+// libssh2 calls of whatever thread is reading the session
+static OPENED: Mutex<Vec<(usize, c_int, usize)>> = Mutex::new(Vec::new());
+
+fn opened(session: *mut raw::LIBSSH2_SESSION, kind: c_int, channel: *mut raw::LIBSSH2_CHANNEL) {
+    // never panic across the ffi boundary
+    if let Ok(mut opened) = OPENED.lock() {
+        opened.push((session as usize, kind, channel as usize));
+    }
+}
+
+extern "C" fn x11_opened(
+    session: *mut raw::LIBSSH2_SESSION,
+    channel: *mut raw::LIBSSH2_CHANNEL,
+    _host: *const c_char,
+    _port: c_int,
+    _abstract: *mut *mut c_void,
+) {
+    opened(session, CALLBACK_X11, channel);
+}
+
+extern "C" fn agent_opened(
+    session: *mut raw::LIBSSH2_SESSION,
+    channel: *mut raw::LIBSSH2_CHANNEL,
+    _abstract: *mut *mut c_void,
+) {
+    opened(session, CALLBACK_AUTHAGENT, channel);
+}
+
+fn session_ptr(session: &Session) -> usize {
+    let mut raw = session.raw();
+    &mut *raw as *mut raw::LIBSSH2_SESSION as usize
+}
+
+// accept channels of this kind, without a callback libssh2 refuses them
+pub fn listen(session: &Session, kind: c_int) {
+    let callback = match kind {
+        CALLBACK_X11 => x11_opened as *mut c_void,
+        _ => agent_opened as *mut c_void,
+    };
+    let mut raw = session.raw();
+    unsafe {
+        libssh2_session_callback_set(&mut *raw, kind, callback);
+    }
+}
+
+// drop the channels nobody accepted for a session that is going away
+pub fn forget(session: &Session) {
+    let ptr = session_ptr(session);
+    let mut opened = OPENED.lock().unwrap();
+    let (mine, others): (Vec<_>, Vec<_>) = opened.drain(..).partition(|(s, _, _)| *s == ptr);
+    *opened = others;
+    drop(opened);
+    for (_, _, channel) in mine {
+        drop(RawChannel {
+            session: session.clone(),
+            raw: channel as *mut raw::LIBSSH2_CHANNEL,
+        });
+    }
+}
+
+// channels of this kind opened since the last call
+pub fn accept(session: &Session, kind: c_int) -> Vec<RawChannel> {
+    let ptr = session_ptr(session);
+    let mut opened = OPENED.lock().unwrap();
+    let (mine, others): (Vec<_>, Vec<_>) = opened
+        .drain(..)
+        .partition(|(s, k, _)| *s == ptr && *k == kind);
+    *opened = others;
+    mine.into_iter()
+        .map(|(_, _, channel)| RawChannel {
+            session: session.clone(),
+            raw: channel as *mut raw::LIBSSH2_CHANNEL,
+        })
+        .collect()
+}
+
+// a libssh2 channel of a non-blocking session, every call locks the session
+pub struct RawChannel {
+    session: Session,
+    raw: *mut raw::LIBSSH2_CHANNEL,
+}
+
+unsafe impl Send for RawChannel {}
+
+impl RawChannel {
+    pub fn open_session(session: &Session) -> Result<RawChannel, String> {
+        let kind = "session";
+        loop {
+            let mut raw = session.raw();
+            let channel = unsafe {
+                raw::libssh2_channel_open_ex(
+                    &mut *raw,
+                    kind.as_ptr() as *const c_char,
+                    kind.len() as c_uint,
+                    raw::LIBSSH2_CHANNEL_WINDOW_DEFAULT,
+                    raw::LIBSSH2_CHANNEL_PACKET_DEFAULT,
+                    null(),
+                    0,
+                )
+            };
+            if !channel.is_null() {
+                return Ok(RawChannel {
+                    session: session.clone(),
+                    raw: channel,
+                });
+            }
+            let errno = unsafe { raw::libssh2_session_last_errno(&mut *raw) };
+            if errno != raw::LIBSSH2_ERROR_EAGAIN {
+                return Err(format!("Cannot open channel: error {errno}"));
+            }
+            drop(raw);
+            thread::sleep(time::Duration::from_millis(WAIT_MS));
+        }
+    }
+    // call f with the session locked until it does not return EAGAIN
+    pub fn retry(&self, f: impl Fn(*mut raw::LIBSSH2_CHANNEL) -> isize) -> isize {
+        loop {
+            let r = {
+                let _lock = self.session.raw();
+                f(self.raw)
+            };
+            if r != raw::LIBSSH2_ERROR_EAGAIN as isize {
+                return r;
+            }
+            thread::sleep(time::Duration::from_millis(WAIT_MS));
+        }
+    }
+    pub fn shell(&self) -> Result<(), String> {
+        let request = "shell";
+        let r = self.retry(|c| unsafe {
+            raw::libssh2_channel_process_startup(
+                c,
+                request.as_ptr() as *const c_char,
+                request.len() as c_uint,
+                null(),
+                0,
+            ) as isize
+        });
+        checked(r, "Cannot start shell")
+    }
+    // 80x24 until the terminal sends its size
+    pub fn request_pty(&self, term: &str) -> Result<(), String> {
+        let r = self.retry(|c| unsafe {
+            raw::libssh2_channel_request_pty_ex(
+                c,
+                term.as_ptr() as *const c_char,
+                term.len() as c_uint,
+                null(),
+                0,
+                80,
+                24,
+                0,
+                0,
+            ) as isize
+        });
+        checked(r, "Cannot request pty")
+    }
+    pub fn request_pty_size(&self, cols: u32, rows: u32) -> Result<(), String> {
+        let r = self.retry(|c| unsafe {
+            raw::libssh2_channel_request_pty_size_ex(c, cols as c_int, rows as c_int, 0, 0) as isize
+        });
+        checked(r, "Cannot resize pty")
+    }
+    pub fn request_auth_agent(&self) -> Result<(), String> {
+        let r = self.retry(|c| unsafe { raw::libssh2_channel_request_auth_agent(c) as isize });
+        checked(r, "Agent forwarding request refused")
+    }
+    pub fn setenv(&self, name: &str, value: &str) -> Result<(), String> {
+        let r = self.retry(|c| unsafe {
+            raw::libssh2_channel_setenv_ex(
+                c,
+                name.as_ptr() as *const c_char,
+                name.len() as c_uint,
+                value.as_ptr() as *const c_char,
+                value.len() as c_uint,
+            ) as isize
+        });
+        checked(r, &format!("Cannot set {name}"))
+    }
+    // Ok(0) is end of file, WouldBlock when there is nothing to read
+    pub fn read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let r = {
+            let _lock = self.session.raw();
+            unsafe {
+                raw::libssh2_channel_read_ex(
+                    self.raw,
+                    0,
+                    buf.as_mut_ptr() as *mut c_char,
+                    buf.len(),
+                )
+            }
+        };
+        match r {
+            r if r >= 0 => Ok(r as usize),
+            r if r == raw::LIBSSH2_ERROR_EAGAIN as isize => Err(ErrorKind::WouldBlock.into()),
+            r => Err(std::io::Error::other(format!("channel read error {r}"))),
+        }
+    }
+    // WouldBlock when the window is full
+    pub fn write(&self, data: &[u8]) -> std::io::Result<usize> {
+        let r = {
+            let _lock = self.session.raw();
+            unsafe {
+                raw::libssh2_channel_write_ex(
+                    self.raw,
+                    0,
+                    data.as_ptr() as *const c_char,
+                    data.len(),
+                )
+            }
+        };
+        match r {
+            r if r >= 0 => Ok(r as usize),
+            r if r == raw::LIBSSH2_ERROR_EAGAIN as isize => Err(ErrorKind::WouldBlock.into()),
+            r => Err(std::io::Error::other(format!("channel write error {r}"))),
+        }
+    }
+    pub fn write_all(&self, mut data: &[u8]) -> std::io::Result<()> {
+        while !data.is_empty() {
+            let r = self.retry(|c| unsafe {
+                raw::libssh2_channel_write_ex(c, 0, data.as_ptr() as *const c_char, data.len())
+            });
+            if r < 0 {
+                return Err(std::io::Error::other(format!("channel write error {r}")));
+            }
+            data = &data[r as usize..];
+        }
+        Ok(())
+    }
+    pub fn eof(&self) -> bool {
+        let _lock = self.session.raw();
+        unsafe { raw::libssh2_channel_eof(self.raw) == 1 }
+    }
+    pub fn send_eof(&self) {
+        self.retry(|c| unsafe { raw::libssh2_channel_send_eof(c) as isize });
+    }
+    pub fn close(&self) -> Result<(), String> {
+        let r = self.retry(|c| unsafe { raw::libssh2_channel_close(c) as isize });
+        checked(r, "Cannot close channel")
+    }
+    // after close, the server has sent everything it had for the channel,
+    // a server that never closes it is given up on after timeout
+    pub fn wait_close(&self, timeout: time::Duration) -> Result<(), String> {
+        let deadline = time::Instant::now() + timeout;
+        loop {
+            let r = {
+                let _lock = self.session.raw();
+                unsafe { raw::libssh2_channel_wait_closed(self.raw) as isize }
+            };
+            if r != raw::LIBSSH2_ERROR_EAGAIN as isize {
+                return checked(r, "Cannot wait for channel close");
+            }
+            if time::Instant::now() >= deadline {
+                return Err("Channel not closed in time".to_string());
+            }
+            thread::sleep(time::Duration::from_millis(WAIT_MS));
+        }
+    }
+    // 0 until the server sent it
+    pub fn exit_status(&self) -> i32 {
+        let _lock = self.session.raw();
+        unsafe { raw::libssh2_channel_get_exit_status(self.raw) }
+    }
+    // the signal name without SIG and the message, when the command was killed
+    pub fn exit_signal(&self) -> (Option<String>, Option<String>) {
+        let mut session = self.session.raw();
+        let (mut signal, mut signal_len) = (null_mut(), 0);
+        let (mut message, mut message_len) = (null_mut(), 0);
+        unsafe {
+            raw::libssh2_channel_get_exit_signal(
+                self.raw,
+                &mut signal,
+                &mut signal_len,
+                &mut message,
+                &mut message_len,
+                null_mut(),
+                null_mut(),
+            );
+        }
+        // the strings are allocated by libssh2
+        let mut take = |ptr: *mut c_char, len: usize| {
+            if ptr.is_null() {
+                return None;
+            }
+            let bytes = unsafe { std::slice::from_raw_parts(ptr as *const u8, len) };
+            let text = String::from_utf8_lossy(bytes).to_string();
+            unsafe { raw::libssh2_free(&mut *session, ptr as *mut c_void) };
+            Some(text)
+        };
+        (take(signal, signal_len), take(message, message_len))
+    }
+}
+
+impl Read for RawChannel {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        RawChannel::read(self, buf)
+    }
+}
+
+impl Write for RawChannel {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        RawChannel::write(self, data)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn checked(r: isize, what: &str) -> Result<(), String> {
+    match r {
+        0 => Ok(()),
+        e => Err(format!("{what}: error {e}")),
+    }
+}
+
+impl Drop for RawChannel {
+    fn drop(&mut self) {
+        self.retry(|c| unsafe { raw::libssh2_channel_close(c) as isize });
+        self.retry(|c| unsafe { raw::libssh2_channel_free(c) as isize });
+    }
+}
+
+// copy both ways between a raw channel and a non-blocking local stream,
+// until either side closes or stop is set
+pub fn pump(
+    channel: RawChannel,
+    mut local: impl Read + Write,
+    stop: &AtomicBool,
+) -> Result<(), String> {
+    let mut buf = vec![0; 32 * 1024];
+    let mut local_open = true;
+    while !stop.load(Ordering::SeqCst) {
+        let mut moved = false;
+
+        // local -> channel
+        while local_open {
+            match local.read(&mut buf) {
+                Ok(0) => {
+                    local_open = false;
+                    channel.send_eof();
+                }
+                Ok(n) => {
+                    channel.write_all(&buf[..n]).map_err(|e| e.to_string())?;
+                    moved = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(format!("Local read error: {e}")),
+            }
+        }
+
+        // channel -> local
+        loop {
+            match channel.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    let mut data = &buf[..n];
+                    while !data.is_empty() {
+                        match local.write(data) {
+                            Ok(0) => return Err("Local write error: closed".to_string()),
+                            Ok(n) => data = &data[n..],
+                            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                                thread::sleep(time::Duration::from_millis(1));
+                            }
+                            Err(e) => return Err(format!("Local write error: {e}")),
+                        }
+                    }
+                    moved = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+
+        if channel.eof() {
+            return Ok(());
+        }
+        if !moved {
+            thread::sleep(time::Duration::from_millis(WAIT_MS));
+        }
+    }
+    Ok(())
+}
+
+// a C string for libssh2, the values here never contain nul bytes
+pub fn c_string(s: &str) -> CString {
+    CString::new(s).unwrap_or_default()
+}
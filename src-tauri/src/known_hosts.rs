@@ -0,0 +1,348 @@
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use ssh2::{CheckResult, HashType, HostKeyType, KnownHostFileKind, Session};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// called for hosts not found in known_hosts, returns true to trust the key
+pub type HostKeyPrompt = dyn Fn(&HostKey) -> bool + Send;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct HostKey {
+    pub host: String,
+    pub port: u16,
+    pub key_type: String,
+    pub fingerprint: String,
+}
+
+#[derive(Debug)]
+pub enum HostKeyError {
+    Changed(HostKey),
+    Rejected(HostKey),
+    Failure(String),
+}
+
+impl std::fmt::Display for HostKeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostKeyError::Changed(k) => write!(
+                f,
+                "Host key changed for {}:{}, {} key fingerprint is {}. \
+                Someone could be eavesdropping, remove the old key from known_hosts if the change is expected",
+                k.host, k.port, k.key_type, k.fingerprint
+            ),
+            HostKeyError::Rejected(k) => write!(
+                f,
+                "Host key not trusted for {}:{}, {} key fingerprint is {}",
+                k.host, k.port, k.key_type, k.fingerprint
+            ),
+            HostKeyError::Failure(e) => write!(f, "Host key verification failed: {e}"),
+        }
+    }
+}
+
+pub fn known_hosts_path() -> PathBuf {
+    let home = dirs::home_dir().unwrap();
+    home.join(".ssh").join("known_hosts")
+}
+
+fn key_type_name(key_type: HostKeyType) -> &'static str {
+    match key_type {
+        HostKeyType::Rsa => "ssh-rsa",
+        HostKeyType::Dss => "ssh-dss",
+        HostKeyType::Ecdsa256 => "ecdsa-sha2-nistp256",
+        HostKeyType::Ecdsa384 => "ecdsa-sha2-nistp384",
+        HostKeyType::Ecdsa521 => "ecdsa-sha2-nistp521",
+        HostKeyType::Ed25519 => "ssh-ed25519",
+        HostKeyType::Unknown => "unknown",
+    }
+}
+
+// host key of a session after the handshake, fingerprint in the openssh format
+pub fn host_key(session: &Session, host: &str, port: u16) -> Result<HostKey, HostKeyError> {
+    let key_type = match session.host_key() {
+        None => return Err(HostKeyError::Failure("No host key".to_string())),
+        Some((_, t)) => t,
+    };
+    let hash = match session.host_key_hash(HashType::Sha256) {
+        None => return Err(HostKeyError::Failure("No host key hash".to_string())),
+        Some(o) => o,
+    };
+    Ok(HostKey {
+        host: host.to_string(),
+        port,
+        key_type: key_type_name(key_type).to_string(),
+        fingerprint: format!("SHA256:{}", STANDARD_NO_PAD.encode(hash)),
+    })
+}
+
+// check the server key against known_hosts, plain and hashed entries,
+// unknown hosts are added when the prompt accepts them, and rejected without a prompt
+pub fn verify(
+    session: &Session,
+    host: &str,
+    port: u16,
+    path: &Path,
+    prompt: Option<&HostKeyPrompt>,
+) -> Result<(), HostKeyError> {
+    let (key, key_type) = match session.host_key() {
+        None => return Err(HostKeyError::Failure("No host key".to_string())),
+        Some(o) => o,
+    };
+    match check(session, host, port, key, path)? {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(HostKeyError::Changed(host_key(session, host, port)?)),
+        CheckResult::Failure => Err(HostKeyError::Failure(format!(
+            "Cannot check host key for {host}:{port}"
+        ))),
+        CheckResult::NotFound => {
+            let host_key = host_key(session, host, port)?;
+            let accepted = match prompt {
+                None => {
+                    println!("no prompt for new host key: {:?}", host_key);
+                    false
+                }
+                Some(prompt) => prompt(&host_key),
+            };
+            if !accepted {
+                return Err(HostKeyError::Rejected(host_key));
+            }
+            add(session, host, port, key, key_type, path)
+        }
+    }
+}
+
+// the key type name a key blob starts with
+fn blob_type(key: &[u8]) -> Option<&str> {
+    let len = u32::from_be_bytes(key.get(..4)?.try_into().ok()?) as usize;
+    std::str::from_utf8(key.get(4..4 + len)?).ok()
+}
+
+// the key type of a known_hosts line, after the optional marker and the host names
+fn line_type(line: &str) -> Option<&str> {
+    let mut fields = line.split_whitespace().skip_while(|f| f.starts_with('@'));
+    fields.next()?;
+    fields.next()
+}
+
+// only entries of the presented key type are compared, libssh2 matches any type
+// so a host known with another key type than the one negotiated looks changed
+fn check(
+    session: &Session,
+    host: &str,
+    port: u16,
+    key: &[u8],
+    path: &Path,
+) -> Result<CheckResult, HostKeyError> {
+    let mut known_hosts = match session.known_hosts() {
+        Err(e) => return Err(HostKeyError::Failure(e.to_string())),
+        Ok(o) => o,
+    };
+    let content = if path.exists() {
+        match std::fs::read_to_string(path) {
+            Err(e) => {
+                let e = format!("Cannot read {}: {e}", path.display());
+                return Err(HostKeyError::Failure(e));
+            }
+            Ok(o) => o,
+        }
+    } else {
+        String::new()
+    };
+    let key_type = blob_type(key);
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line_type(line) != key_type {
+            continue;
+        }
+        if let Err(e) = known_hosts.read_str(line, KnownHostFileKind::OpenSSH) {
+            println!("skipping known_hosts line {line}: {e}");
+        }
+    }
+    Ok(known_hosts.check_port(host, port, key))
+}
+
+// append the key to known_hosts, the rest of the file is left untouched
+fn add(
+    session: &Session,
+    host: &str,
+    port: u16,
+    key: &[u8],
+    key_type: HostKeyType,
+    path: &Path,
+) -> Result<(), HostKeyError> {
+    let failure = |e: String| HostKeyError::Failure(format!("Cannot add host key: {e}"));
+
+    let name = if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{host}]:{port}")
+    };
+    let mut known_hosts = session.known_hosts().map_err(|e| failure(e.to_string()))?;
+    known_hosts
+        .add(&name, key, "", key_type.into())
+        .map_err(|e| failure(e.to_string()))?;
+    let hosts = known_hosts.hosts().map_err(|e| failure(e.to_string()))?;
+    let mut line = known_hosts
+        .write_string(&hosts[0], KnownHostFileKind::OpenSSH)
+        .map_err(|e| failure(e.to_string()))?;
+    if !line.ends_with('\n') {
+        line.push('\n');
+    }
+    // do not glue the new entry to a last line without newline
+    let content = std::fs::read(path).unwrap_or_default();
+    if !content.is_empty() && !content.ends_with(b"\n") {
+        line.insert(0, '\n');
+    }
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| failure(e.to_string()))?;
+    }
+    let mut f = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| failure(e.to_string()))?;
+    f.write_all(line.as_bytes())
+        .map_err(|e| failure(e.to_string()))?;
+    println!("host key added to {}: {}", path.display(), line.trim());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::net::TcpStream;
+    const PORT: u16 = 22;
+
+    fn handshake() -> (Session, String) {
+        let host = env::var("TEST_SSH_HOST").unwrap();
+        let tcp = TcpStream::connect(format!("{host}:{PORT}")).unwrap();
+        let mut session = Session::new().unwrap();
+        session.set_tcp_stream(tcp);
+        session.handshake().unwrap();
+        (session, host)
+    }
+
+    fn known_hosts_file(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn unknown_host_rejected() {
+        let (session, host) = handshake();
+        let path = known_hosts_file("xtauri_known_hosts_rejected");
+        let r = verify(&session, &host, PORT, &path, Some(&|_: &HostKey| false));
+        assert!(matches!(r, Err(HostKeyError::Rejected(_))));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn unknown_host_without_prompt() {
+        let (session, host) = handshake();
+        let path = known_hosts_file("xtauri_known_hosts_no_prompt");
+        let r = verify(&session, &host, PORT, &path, None);
+        assert!(matches!(r, Err(HostKeyError::Rejected(_))));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn unknown_host_accepted() {
+        let (session, host) = handshake();
+        let path = known_hosts_file("xtauri_known_hosts_accepted");
+        let r = verify(&session, &host, PORT, &path, Some(&|_: &HostKey| true));
+        assert!(r.is_ok());
+        assert!(std::fs::read_to_string(&path).unwrap().starts_with(&host));
+
+        // now known, the prompt is not called again
+        let r = verify(&session, &host, PORT, &path, Some(&|_: &HostKey| false));
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn changed_host_key() {
+        let (session, host) = handshake();
+        let path = known_hosts_file("xtauri_known_hosts_changed");
+        // same key type, different key
+        let (key, key_type) = session.host_key().unwrap();
+        let mut key = key.to_vec();
+        let last = key.len() - 1;
+        key[last] ^= 0xff;
+        let line = format!(
+            "{host} {} {}\n",
+            key_type_name(key_type),
+            base64::engine::general_purpose::STANDARD.encode(&key)
+        );
+        std::fs::write(&path, line).unwrap();
+        let r = verify(&session, &host, PORT, &path, Some(&|_: &HostKey| true));
+        assert!(matches!(r, Err(HostKeyError::Changed(_))));
+    }
+
+    // an ssh key blob, the key type followed by the key data
+    fn blob(key_type: &str, data: &[u8]) -> Vec<u8> {
+        let mut blob = Vec::new();
+        for part in [key_type.as_bytes(), data] {
+            blob.extend_from_slice(&(part.len() as u32).to_be_bytes());
+            blob.extend_from_slice(part);
+        }
+        blob
+    }
+
+    #[test]
+    fn other_key_types_ignored() {
+        let session = Session::new().unwrap();
+        let path = known_hosts_file("xtauri_known_hosts_types");
+        let ed25519 = blob("ssh-ed25519", &[1; 32]);
+        let ecdsa = blob("ecdsa-sha2-nistp256", &[2; 65]);
+        let encode = |key: &[u8]| base64::engine::general_purpose::STANDARD.encode(key);
+        let content = format!(
+            "# comment\nexample.com ssh-ed25519 {}\n[example.com]:2222 ssh-ed25519 {}\n",
+            encode(&ed25519),
+            encode(&ed25519)
+        );
+        std::fs::write(&path, content).unwrap();
+        let r = |host, port, key: &[u8]| {
+            format!("{:?}", check(&session, host, port, key, &path).unwrap())
+        };
+
+        // a host known with ed25519 that presents ecdsa is not known for ecdsa
+        assert_eq!(r("example.com", 22, &ecdsa), "NotFound");
+        assert_eq!(r("example.com", 22, &ed25519), "Match");
+        assert_eq!(r("example.com", 2222, &ed25519), "Match");
+        let other = blob("ssh-ed25519", &[3; 32]);
+        assert_eq!(r("example.com", 22, &other), "Mismatch");
+        assert_eq!(r("other.com", 22, &ed25519), "NotFound");
+
+        // hashed entries like HashKnownHosts
+        let (_, _, status) = crate::command::run(&format!("ssh-keygen -H -f {}", path.display()));
+        assert_eq!(status, 0);
+        assert!(std::fs::read_to_string(&path).unwrap().contains("|1|"));
+        assert_eq!(r("example.com", 22, &ed25519), "Match");
+        assert_eq!(r("example.com", 2222, &ed25519), "Match");
+        assert_eq!(r("example.com", 22, &ecdsa), "NotFound");
+        assert_eq!(r("example.com", 22, &other), "Mismatch");
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(path.with_extension("old"));
+    }
+
+    #[test]
+    fn key_type_fields() {
+        assert_eq!(blob_type(&blob("ssh-rsa", &[1, 2])), Some("ssh-rsa"));
+        assert_eq!(blob_type(&[0, 0, 0, 9, b'a']), None);
+        assert_eq!(line_type("host ssh-ed25519 AAAA"), Some("ssh-ed25519"));
+        assert_eq!(line_type("@revoked host ssh-rsa AAAA"), Some("ssh-rsa"));
+        assert_eq!(line_type("host"), None);
+    }
+
+    #[test]
+    fn fingerprint() {
+        let (session, host) = handshake();
+        let key = host_key(&session, &host, PORT).unwrap();
+        assert!(key.fingerprint.starts_with("SHA256:"));
+        assert_eq!(key.fingerprint.len(), 7 + 43);
+    }
+}
@@ -3,22 +3,48 @@
     windows_subsystem = "windows"
 )]
 
+mod agent;
+mod auth;
 mod command;
+mod error;
+mod files;
+mod forward;
+mod incoming;
+mod known_hosts;
+mod progress;
+mod prompt;
 mod settings;
 mod ssh;
+mod transfer;
+mod tree;
+mod tunnel;
+mod x11;
 
-use std::io::{Read, Write};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::time;
-use tauri::{Emitter, Manager, State, Window};
+use tauri::{Emitter, Manager, State};
 // use tokio::sync::Mutex;
 use mio::net::TcpStream;
 use mio::{Events, Interest, Poll, Token};
 use std::sync::{Arc, Mutex};
 //use polling::{Event, Events, Poller};
+use error::SshError;
+use files::FileEntry;
+use progress::{Progress, TransferProgress};
 use settings::Settings;
+use transfer::{Direction, ResumeOptions, TransferInfo, TransferOptions};
+use tree::SymlinkPolicy;
 
 const WAIT_MS: u64 = 50;
 
+// terminal poll timeout, like tunnel::pump
+const POLL_MS: u64 = 20;
+
+// reconnect attempts after a connection drops, waiting 1, 2, 4... seconds in between
+const RECONNECT_ATTEMPTS: u32 = 8;
+const RECONNECT_MAX_SECS: u64 = 30;
+
 // use serde::{Deserialize, Serialize};
 // use chrono::prelude::{DateTime, NaiveDateTime, Utc};
 
@@ -28,65 +54,239 @@ extern crate objc;
 #[cfg(target_os = "linux")]
 extern crate webkit2gtk;
 
+// one connected host, open_terminal adds a writer per terminal channel
 #[derive(Default)]
-struct AppState {
+struct Session {
     ssh: Mutex<ssh::Ssh>,
-    connected: Mutex<bool>,
-    itx: Mutex<Option<std::sync::mpsc::Sender<String>>>,
+    itx: Mutex<HashMap<u32, std::sync::mpsc::Sender<String>>>,
+    reading: AtomicBool,
+}
+
+#[derive(Default)]
+struct AppState {
+    sessions: Mutex<HashMap<u32, Arc<Session>>>,
+    next_id: AtomicU32,
+    transfers: transfer::Transfers,
+    prompts: prompt::Prompts,
+}
+
+impl AppState {
+    fn add_session(&self, ssh: ssh::Ssh, app: tauri::AppHandle) -> u32 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let session = Arc::new(Session {
+            ssh: Mutex::new(ssh),
+            ..Default::default()
+        });
+        self.sessions.lock().unwrap().insert(id, Arc::clone(&session));
+        keepalive(id, session, app);
+        id
+    }
+    fn session(&self, id: u32) -> Result<Arc<Session>, SshError> {
+        match self.sessions.lock().unwrap().get(&id) {
+            None => Err(SshError::Connect(format!("Session {id} not found"))),
+            Some(s) => Ok(Arc::clone(s)),
+        }
+    }
+    fn remove_session(&self, id: u32) -> Result<Arc<Session>, SshError> {
+        match self.sessions.lock().unwrap().remove(&id) {
+            None => Err(SshError::Connect(format!("Session {id} not found"))),
+            Some(s) => Ok(s),
+        }
+    }
 }
 
 // the payload type must implement `Serialize` and `Clone`.
 #[derive(Clone, serde::Serialize)]
 struct Payload {
+    id: u32,
+    channel: u32,
     data: Vec<u8>,
     // data: String,
 }
 
+#[derive(Clone, serde::Serialize)]
+struct TerminalExited {
+    id: u32,
+    channel: u32,
+    #[serde(flatten)]
+    exit: ssh::TerminalExit,
+}
+
+// connection-dead is emitted when keepalives got no reply, then it reconnects,
+// connection-lost is emitted when the connection drops and after every failed
+// attempt, retrying is false once it gives up, connection-restored when it is back
+#[derive(Clone, serde::Serialize)]
+struct DeadConnection {
+    id: u32,
+    missed: u32,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct ConnectionEvent {
+    id: u32,
+    attempt: u32,
+    retrying: bool,
+    error: String,
+}
+
+#[tauri::command]
+fn read_settings() -> Result<Settings, SshError> {
+    settings::read_settings().map_err(SshError::Io)
+}
+
 #[tauri::command]
-fn read_settings() -> Result<Settings, String> {
-    settings::read_settings()
+fn write_settings(settings: Settings) -> Result<(), SshError> {
+    settings::write_settings(settings).map_err(SshError::Io)
+}
+
+// hops of an openssh ProxyJump value, for the settings jump list
+#[tauri::command]
+fn parse_proxy_jump(spec: &str, user: &str) -> Result<Vec<settings::Jump>, SshError> {
+    settings::parse_proxy_jump(spec, user).map_err(SshError::Connect)
+}
+
+// unknown host keys are shown to the user, who answers with confirm_host_key
+fn host_key_prompt(
+    state: &AppState,
+    app: tauri::AppHandle,
+) -> impl Fn(&known_hosts::HostKey) -> bool + Clone + Send + 'static {
+    let prompts = state.prompts.clone();
+    move |key| {
+        matches!(
+            prompts.ask(&app, "host-key-confirm", key.clone()),
+            Ok(Some(_))
+        )
+    }
+}
+
+// server challenges are shown to the user too, who answers with
+// keyboard_interactive_response
+fn new_ssh(settings: &Settings, state: &AppState, app: tauri::AppHandle) -> ssh::Ssh {
+    let mut ssh = ssh::Ssh::new();
+    ssh.set_jumps(settings.jumps.clone());
+    ssh.set_proxy_command(&settings.proxy_command);
+    ssh.set_x11_forwarding(settings.x11_forwarding);
+    ssh.set_agent_forwarding(settings.forwards_agent());
+    ssh.set_env(settings.env.clone());
+    ssh.set_startup(&settings.working_dir, &settings.startup_command);
+    ssh.set_keepalive(settings.keepalive_interval, settings.keepalive_count_max);
+    ssh.set_host_key_prompt(host_key_prompt(state, app.clone()));
+    let prompts = state.prompts.clone();
+    ssh.set_challenge_prompt(move |challenge| {
+        match prompts.ask(&app, "keyboard-interactive", challenge.clone()) {
+            Err(e) => {
+                println!("{e}");
+                None
+            }
+            Ok(responses) => responses,
+        }
+    });
+    ssh
 }
 
 #[tauri::command]
-fn write_settings(settings: Settings) -> Result<(), String> {
-    settings::write_settings(settings)
+fn confirm_host_key(prompt: u32, accept: bool, state: State<'_, AppState>) -> Result<(), SshError> {
+    state
+        .prompts
+        .answer(prompt, accept.then(Vec::new))
+        .map_err(SshError::HostKey)
+}
+
+#[tauri::command]
+fn keyboard_interactive_response(
+    prompt: u32,
+    responses: Option<Vec<String>>,
+    state: State<'_, AppState>,
+) -> Result<(), SshError> {
+    state
+        .prompts
+        .answer(prompt, responses)
+        .map_err(SshError::Auth)
+}
+
+#[derive(Clone, serde::Serialize)]
+struct Connected {
+    id: u32,
+    auth: auth::AuthMethod,
+}
+
+#[tauri::command]
+async fn connect(
+    settings: Settings,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<Connected, SshError> {
+    let mut _ssh = new_ssh(&settings, &state, app.clone());
+    let keys: Vec<String> = settings
+        .private_key
+        .iter()
+        .filter(|k| !k.is_empty())
+        .cloned()
+        .collect();
+    let password = settings.password.as_deref().filter(|p| !p.is_empty());
+
+    match _ssh
+        .connect(
+            settings.server.as_str(),
+            settings.port,
+            settings.user.as_str(),
+            &keys,
+            settings.passphrase.as_deref(),
+            password,
+        )
+        .await
+    {
+        Err(e) => {
+            println!("{e}");
+            Err(e)
+        }
+        Ok(auth) => {
+            write_settings(settings)?;
+            let id = state.add_session(_ssh, app);
+            println!("Connected, session: {id}");
+            Ok(Connected { id, auth })
+        }
+    }
 }
 
 #[tauri::command]
 async fn connect_with_password(
     settings: Settings,
     state: State<'_, AppState>,
-) -> Result<(), String> {
-    let mut _ssh = ssh::Ssh::new();
+    app: tauri::AppHandle,
+) -> Result<u32, SshError> {
+    let mut _ssh = new_ssh(&settings, &state, app.clone());
     match _ssh
         .connect_with_password(
             settings.server.as_str(),
             settings.port,
             settings.user.as_str(),
-            settings.password.clone().unwrap().as_str(),
+            settings.password.as_deref().unwrap_or_default(),
         )
         .await
     {
         Err(e) => Err(e),
         Ok(_) => {
-            write_settings(settings).expect("Cannot write settings");
-            let mut ssh = state.ssh.lock().unwrap();
-            *ssh = _ssh;
-            *state.connected.lock().unwrap() = true;
-            println!("Connected");
-            let output = ssh.run("whoami").unwrap();
+            write_settings(settings)?;
+            let output = _ssh.run("whoami")?;
             println!("{}", output);
-            Ok(())
+            let id = state.add_session(_ssh, app);
+            println!("Connected, session: {id}");
+            Ok(id)
         }
     }
 }
 
 #[tauri::command]
-async fn connect_with_key(settings: Settings, state: State<'_, AppState>) -> Result<(), String> {
-    let mut _ssh = ssh::Ssh::new();
-    let mut pkey = String::new();
+async fn connect_with_key(
+    settings: Settings,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<u32, SshError> {
+    let mut _ssh = new_ssh(&settings, &state, app.clone());
+    let mut pkey = settings.private_key.clone().unwrap_or_default();
 
-    if settings.private_key.clone().unwrap().is_empty() {
+    if pkey.is_empty() {
         pkey = String::from(ssh::Ssh::private_key_path().to_string_lossy());
     }
 
@@ -96,6 +296,7 @@ async fn connect_with_key(settings: Settings, state: State<'_, AppState>) -> Res
             settings.port,
             settings.user.as_str(),
             pkey.as_str(),
+            settings.passphrase.as_deref(),
         )
         .await
     {
@@ -104,265 +305,938 @@ async fn connect_with_key(settings: Settings, state: State<'_, AppState>) -> Res
             Err(e)
         }
         Ok(_) => {
-            write_settings(settings).expect("Cannot write settings");
-            let mut ssh = state.ssh.lock().unwrap();
-            *ssh = _ssh;
-            *state.connected.lock().unwrap() = true;
-            println!("Connected");
-            let output = ssh.run("whoami").unwrap();
+            write_settings(settings)?;
+            let output = _ssh.run("whoami")?;
             println!("{}", output);
-            Ok(())
+            let id = state.add_session(_ssh, app);
+            println!("Connected, session: {id}");
+            Ok(id)
         }
     }
 }
 
 #[tauri::command]
-async fn disconnect(state: State<'_, AppState>) -> Result<(), String> {
-    let mut ssh = state.ssh.lock().unwrap();
+async fn connect_with_agent(
+    settings: Settings,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<u32, SshError> {
+    let mut _ssh = new_ssh(&settings, &state, app.clone());
+    match _ssh
+        .connect_with_agent(settings.server.as_str(), settings.port, settings.user.as_str())
+        .await
+    {
+        Err(e) => {
+            println!("{e}");
+            Err(e)
+        }
+        Ok(_) => {
+            write_settings(settings)?;
+            let id = state.add_session(_ssh, app);
+            println!("Connected with agent, session: {id}");
+            Ok(id)
+        }
+    }
+}
+
+#[tauri::command]
+async fn connect_with_keyboard_interactive(
+    settings: Settings,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<u32, SshError> {
+    let mut _ssh = new_ssh(&settings, &state, app.clone());
+    match _ssh
+        .connect_with_keyboard_interactive(
+            settings.server.as_str(),
+            settings.port,
+            settings.user.as_str(),
+        )
+        .await
+    {
+        Err(e) => {
+            println!("{e}");
+            Err(e)
+        }
+        Ok(_) => {
+            write_settings(settings)?;
+            let id = state.add_session(_ssh, app);
+            println!("Connected with keyboard-interactive, session: {id}");
+            Ok(id)
+        }
+    }
+}
+
+#[tauri::command]
+async fn disconnect(id: u32, state: State<'_, AppState>) -> Result<(), SshError> {
+    let session = state.remove_session(id)?;
+    state.transfers.cancel_session(id);
+    let mut ssh = session.ssh.lock().unwrap();
     ssh.disconnect()
 }
 
 #[tauri::command]
-async fn setup_ssh(settings: Settings) -> Result<(), String> {
+async fn setup_ssh(
+    settings: Settings,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<(), SshError> {
     let host = settings.server.as_str();
     let port = settings.port;
     let user = settings.user.as_str();
-    let password = settings.password.unwrap();
-    ssh::Ssh::setup_ssh(host, port, user, &password).await
+    let password = settings.password.unwrap_or_default();
+    let prompt = host_key_prompt(&state, app);
+    ssh::Ssh::setup_ssh(host, port, user, &password, prompt).await
 }
 
 #[tauri::command]
-async fn ssh_run(command: String, state: State<'_, AppState>) -> Result<String, String> {
-    let mut ssh = state.ssh.lock().unwrap();
+async fn ssh_run(id: u32, command: String, state: State<'_, AppState>) -> Result<String, SshError> {
+    let session = state.session(id)?;
+    let mut ssh = session.ssh.lock().unwrap();
     ssh.run(&command)
 }
 
+// transfer-started comes first with the id of the transfer, then transfer-progress
+// events and at the end transfer-complete or transfer-error, queued transfers
+// also send transfer-updated whenever their state changes
+#[derive(Clone, serde::Serialize)]
+struct TransferStarted {
+    transfer: u32,
+    id: u32,
+    kind: Direction,
+    localpath: String,
+    remotepath: String,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct TransferComplete {
+    transfer: u32,
+    bytes: u64,
+    // seconds
+    elapsed: f64,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct TransferError {
+    transfer: u32,
+    error: SshError,
+}
+
+fn start_transfer(
+    app: &tauri::AppHandle,
+    started: TransferStarted,
+    on_progress: impl Fn(&TransferProgress) + Send + 'static,
+) -> Progress {
+    let transfer = started.transfer;
+    let _ = app.emit("transfer-started", started);
+    let app = app.clone();
+    Progress::new(transfer, move |p| {
+        on_progress(&p);
+        let _ = app.emit("transfer-progress", p);
+    })
+}
+
+fn end_transfer<T>(app: &tauri::AppHandle, progress: &Progress, result: &Result<T, SshError>) {
+    let transfer = progress.transfer();
+    let _ = match result {
+        Err(e) => app.emit(
+            "transfer-error",
+            TransferError {
+                transfer,
+                error: e.clone(),
+            },
+        ),
+        Ok(_) => app.emit(
+            "transfer-complete",
+            TransferComplete {
+                transfer,
+                bytes: progress.bytes(),
+                elapsed: progress.elapsed().as_secs_f64(),
+            },
+        ),
+    };
+}
+
+// copy one file right away, on its own channel so terminals keep working
+fn run_transfer(
+    app: &tauri::AppHandle,
+    state: &AppState,
+    id: u32,
+    kind: Direction,
+    localpath: &str,
+    remotepath: &str,
+) -> Result<String, SshError> {
+    let session = state.session(id)?;
+    let session = session.ssh.lock().unwrap().connected()?.clone();
+    let started = TransferStarted {
+        transfer: state.transfers.next_transfer(),
+        id,
+        kind,
+        localpath: localpath.to_string(),
+        remotepath: remotepath.to_string(),
+    };
+    let mut progress = start_transfer(app, started, |_| {});
+    let control = transfer::Control::default();
+    let result = match kind {
+        Direction::Download => {
+            transfer::download(&session, remotepath, localpath, &mut progress, &control)
+        }
+        Direction::Upload => {
+            transfer::upload(&session, localpath, remotepath, &mut progress, &control)
+        }
+    };
+    end_transfer(app, &progress, &result);
+    result?;
+    serde_json::to_string("done").map_err(|e| SshError::Io(e.to_string()))
+}
+
 #[tauri::command]
 async fn download(
+    id: u32,
     remotepath: String,
     localpath: String,
-    window: Window,
+    app: tauri::AppHandle,
     state: State<'_, AppState>,
-) -> Result<String, String> {
-    let mut ssh = state.ssh.lock().unwrap();
-    match ssh.scp_download(&remotepath, &localpath, window) {
-        Err(e) => Err(e),
-        Ok(o) => {
-            println!("file saved to: {localpath}");
-            Ok(serde_json::to_string(&o).unwrap())
-        }
+) -> Result<String, SshError> {
+    let result = run_transfer(
+        &app,
+        &state,
+        id,
+        Direction::Download,
+        &localpath,
+        &remotepath,
+    );
+    if result.is_ok() {
+        println!("file saved to: {localpath}");
     }
+    result
 }
 
 #[tauri::command]
 async fn upload(
+    id: u32,
     localpath: String,
     remotepath: String,
-    window: Window,
+    app: tauri::AppHandle,
     state: State<'_, AppState>,
-) -> Result<String, String> {
-    let mut ssh = state.ssh.lock().unwrap();
-    match ssh.scp_upload(&localpath, &remotepath, window) {
-        Err(e) => Err(e),
-        Ok(o) => {
-            println!("file uploaded to: {remotepath}");
-            Ok(serde_json::to_string(&o).unwrap())
-        }
+) -> Result<String, SshError> {
+    let result = run_transfer(&app, &state, id, Direction::Upload, &localpath, &remotepath);
+    if result.is_ok() {
+        println!("file uploaded to: {remotepath}");
+    }
+    result
+}
+
+// start queued transfers while fewer than the concurrency are running,
+// each one on a thread of its own, called again when one ends
+fn run_transfers(app: &tauri::AppHandle) {
+    let state = app.state::<AppState>();
+    for job in state.transfers.next_jobs() {
+        let app = app.clone();
+        std::thread::spawn(move || {
+            let _ = app.emit("transfer-updated", job.info());
+            let state = app.state::<AppState>();
+            let session = state
+                .session(job.id())
+                .and_then(|s| s.ssh.lock().unwrap().connected().cloned());
+            let started = TransferStarted {
+                transfer: job.transfer(),
+                id: job.id(),
+                kind: job.kind(),
+                localpath: job.localpath().to_string(),
+                remotepath: job.remotepath().to_string(),
+            };
+            let progress_job = Arc::clone(&job);
+            let mut progress = start_transfer(&app, started, move |p| progress_job.set_progress(p));
+            let result = session.and_then(|session| job.run(&session, &mut progress));
+            end_transfer(&app, &progress, &result);
+            let info = state.transfers.finish(&job, result);
+            println!("transfer {} {:?}", info.transfer, info.state);
+            let _ = app.emit("transfer-updated", info);
+            run_transfers(&app);
+        });
     }
 }
 
+fn queue_transfer(
+    app: &tauri::AppHandle,
+    state: &AppState,
+    id: u32,
+    kind: Direction,
+    localpath: &str,
+    remotepath: &str,
+    options: TransferOptions,
+) -> Result<TransferInfo, SshError> {
+    state.session(id)?;
+    let info = state
+        .transfers
+        .add(id, kind, localpath, remotepath, options);
+    let _ = app.emit("transfer-updated", info.clone());
+    run_transfers(app);
+    Ok(info)
+}
+
+#[tauri::command]
+async fn queue_download(
+    id: u32,
+    remotepath: String,
+    localpath: String,
+    resume: Option<ResumeOptions>,
+    app: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<TransferInfo, SshError> {
+    let kind = Direction::Download;
+    let options = TransferOptions {
+        resume,
+        symlinks: None,
+    };
+    queue_transfer(&app, &state, id, kind, &localpath, &remotepath, options)
+}
+
+#[tauri::command]
+async fn queue_upload(
+    id: u32,
+    localpath: String,
+    remotepath: String,
+    resume: Option<ResumeOptions>,
+    app: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<TransferInfo, SshError> {
+    let kind = Direction::Upload;
+    let options = TransferOptions {
+        resume,
+        symlinks: None,
+    };
+    queue_transfer(&app, &state, id, kind, &localpath, &remotepath, options)
+}
+
+// directory transfers, symlinks are followed unless the policy says otherwise
+#[tauri::command]
+async fn queue_download_dir(
+    id: u32,
+    remotepath: String,
+    localpath: String,
+    symlinks: Option<SymlinkPolicy>,
+    app: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<TransferInfo, SshError> {
+    let kind = Direction::Download;
+    let options = TransferOptions {
+        resume: None,
+        symlinks: Some(symlinks.unwrap_or_default()),
+    };
+    queue_transfer(&app, &state, id, kind, &localpath, &remotepath, options)
+}
+
+#[tauri::command]
+async fn queue_upload_dir(
+    id: u32,
+    localpath: String,
+    remotepath: String,
+    symlinks: Option<SymlinkPolicy>,
+    app: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<TransferInfo, SshError> {
+    let kind = Direction::Upload;
+    let options = TransferOptions {
+        resume: None,
+        symlinks: Some(symlinks.unwrap_or_default()),
+    };
+    queue_transfer(&app, &state, id, kind, &localpath, &remotepath, options)
+}
+
+#[tauri::command]
+fn list_transfers(state: State<'_, AppState>) -> Vec<TransferInfo> {
+    state.transfers.list()
+}
+
 #[tauri::command]
-async fn send_key(key: String, state: State<'_, AppState>) -> Result<(), String> {
+fn pause_transfer(
+    transfer: u32,
+    app: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<TransferInfo, SshError> {
+    let info = state.transfers.pause(transfer)?;
+    let _ = app.emit("transfer-updated", info.clone());
+    Ok(info)
+}
+
+#[tauri::command]
+fn resume_transfer(
+    transfer: u32,
+    app: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<TransferInfo, SshError> {
+    let info = state.transfers.resume(transfer)?;
+    let _ = app.emit("transfer-updated", info.clone());
+    run_transfers(&app);
+    Ok(info)
+}
+
+#[tauri::command]
+fn cancel_transfer(
+    transfer: u32,
+    app: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<TransferInfo, SshError> {
+    let info = state.transfers.cancel(transfer)?;
+    let _ = app.emit("transfer-updated", info.clone());
+    Ok(info)
+}
+
+// queue a failed or cancelled transfer again
+#[tauri::command]
+fn retry_transfer(
+    transfer: u32,
+    app: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<TransferInfo, SshError> {
+    let info = state.transfers.retry(transfer)?;
+    let _ = app.emit("transfer-updated", info.clone());
+    run_transfers(&app);
+    Ok(info)
+}
+
+// drop finished transfers from the list
+#[tauri::command]
+fn clear_transfers(state: State<'_, AppState>) {
+    state.transfers.clear();
+}
+
+#[tauri::command]
+fn set_transfer_concurrency(concurrency: usize, app: tauri::AppHandle, state: State<'_, AppState>) {
+    state.transfers.set_concurrency(concurrency);
+    run_transfers(&app);
+}
+
+#[tauri::command]
+async fn send_key(
+    id: u32,
+    channel: u32,
+    key: String,
+    state: State<'_, AppState>,
+) -> Result<(), SshError> {
     //println!("key: {key}");
-    let mutex = state.itx.lock().unwrap();
-    mutex.as_ref().unwrap().send(key).map_err(|e| e.to_string())
+    let session = state.session(id)?;
+    let itx = session.itx.lock().unwrap();
+    match itx.get(&channel) {
+        None => Err(SshError::Channel(format!("Terminal {channel} not found"))),
+        Some(itx) => itx
+            .send(key)
+            .map_err(|_| SshError::Channel(format!("Terminal {channel} closed"))),
+    }
+}
+
+// bind_host defaults to localhost, bind_port 0 picks a free port
+#[tauri::command]
+async fn add_local_forward(
+    id: u32,
+    bind_host: Option<String>,
+    bind_port: u16,
+    host: String,
+    port: u16,
+    state: State<'_, AppState>,
+) -> Result<u32, SshError> {
+    let session = state.session(id)?;
+    let mut ssh = session.ssh.lock().unwrap();
+    let bind_host = bind_host.unwrap_or_else(|| "127.0.0.1".to_string());
+    ssh.forward_local(&bind_host, bind_port, &host, port)
+}
+
+// bind_host is the remote listen address, bind_port 0 lets the server pick one
+#[tauri::command]
+async fn add_remote_forward(
+    id: u32,
+    bind_host: Option<String>,
+    bind_port: u16,
+    host: String,
+    port: u16,
+    state: State<'_, AppState>,
+) -> Result<u32, SshError> {
+    let session = state.session(id)?;
+    let mut ssh = session.ssh.lock().unwrap();
+    let bind_host = bind_host.unwrap_or_else(|| "localhost".to_string());
+    ssh.forward_remote(&bind_host, bind_port, &host, port)
+}
+
+// socks5 proxy through the session, stopped with remove_forward
+#[tauri::command]
+async fn start_socks_proxy(
+    id: u32,
+    bind_host: Option<String>,
+    bind_port: u16,
+    state: State<'_, AppState>,
+) -> Result<u32, SshError> {
+    let session = state.session(id)?;
+    let mut ssh = session.ssh.lock().unwrap();
+    let bind_host = bind_host.unwrap_or_else(|| "127.0.0.1".to_string());
+    ssh.forward_dynamic(&bind_host, bind_port)
 }
 
 #[tauri::command]
-async fn resize(cols: u32, rows: u32, state: State<'_, AppState>) -> Result<(), String> {
+async fn list_forwards(
+    id: u32,
+    state: State<'_, AppState>,
+) -> Result<Vec<forward::ForwardInfo>, SshError> {
+    let session = state.session(id)?;
+    let ssh = session.ssh.lock().unwrap();
+    Ok(ssh.forward_list())
+}
+
+#[tauri::command]
+async fn remove_forward(id: u32, forward: u32, state: State<'_, AppState>) -> Result<(), SshError> {
+    let session = state.session(id)?;
+    let mut ssh = session.ssh.lock().unwrap();
+    ssh.forward_remove(forward)
+}
+
+// remote file browser, paths are absolute or relative to the login directory
+#[tauri::command]
+async fn sftp_stat(
+    id: u32,
+    path: String,
+    state: State<'_, AppState>,
+) -> Result<FileEntry, SshError> {
+    let session = state.session(id)?;
+    let mut ssh = session.ssh.lock().unwrap();
+    ssh.sftp_entry(&path)
+}
+
+#[tauri::command]
+async fn sftp_readdir(
+    id: u32,
+    path: String,
+    state: State<'_, AppState>,
+) -> Result<Vec<FileEntry>, SshError> {
+    let session = state.session(id)?;
+    let mut ssh = session.ssh.lock().unwrap();
+    ssh.sftp_list(&path)
+}
+
+#[tauri::command]
+async fn sftp_mkdir(id: u32, path: String, state: State<'_, AppState>) -> Result<(), SshError> {
+    let session = state.session(id)?;
+    let mut ssh = session.ssh.lock().unwrap();
+    ssh.sftp_mkdir(&path)
+}
+
+#[tauri::command]
+async fn sftp_rmdir(id: u32, path: String, state: State<'_, AppState>) -> Result<(), SshError> {
+    let session = state.session(id)?;
+    let mut ssh = session.ssh.lock().unwrap();
+    ssh.sftp_rmdir(&path)
+}
+
+// an empty file
+#[tauri::command]
+async fn sftp_create(id: u32, path: String, state: State<'_, AppState>) -> Result<(), SshError> {
+    let session = state.session(id)?;
+    let mut ssh = session.ssh.lock().unwrap();
+    ssh.sftp_create(&path).map(|_| ())
+}
+
+#[tauri::command]
+async fn sftp_rename(
+    id: u32,
+    src: String,
+    dst: String,
+    state: State<'_, AppState>,
+) -> Result<(), SshError> {
+    let session = state.session(id)?;
+    let mut ssh = session.ssh.lock().unwrap();
+    ssh.sftp_rename(&src, &dst)
+}
+
+// files, links and whole directories
+#[tauri::command]
+async fn sftp_delete(id: u32, path: String, state: State<'_, AppState>) -> Result<(), SshError> {
+    let session = state.session(id)?;
+    let mut ssh = session.ssh.lock().unwrap();
+    ssh.sftp_delete(&path)
+}
+
+#[tauri::command]
+async fn sftp_readlink(
+    id: u32,
+    path: String,
+    state: State<'_, AppState>,
+) -> Result<String, SshError> {
+    let session = state.session(id)?;
+    let mut ssh = session.ssh.lock().unwrap();
+    ssh.sftp_readlink(&path)
+}
+
+// the canonical path with links resolved, "." is the login directory
+#[tauri::command]
+async fn sftp_realpath(
+    id: u32,
+    path: String,
+    state: State<'_, AppState>,
+) -> Result<FileEntry, SshError> {
+    let session = state.session(id)?;
+    let mut ssh = session.ssh.lock().unwrap();
+    let (path, stat) = ssh.sftp_realpath(&path)?;
+    Ok(FileEntry::new(std::path::Path::new(&path), &stat))
+}
+
+#[tauri::command]
+async fn sftp_save(
+    id: u32,
+    path: String,
+    data: String,
+    state: State<'_, AppState>,
+) -> Result<(), SshError> {
+    let session = state.session(id)?;
+    let mut ssh = session.ssh.lock().unwrap();
+    ssh.sftp_save(&path, &data)
+}
+
+#[tauri::command]
+async fn resize(
+    id: u32,
+    channel: u32,
+    cols: u32,
+    rows: u32,
+    state: State<'_, AppState>,
+) -> Result<(), SshError> {
     //println!("resize: {cols}x{rows}");
-    let mut ssh = state.ssh.lock().unwrap();
-    ssh.channel_shell_size(cols, rows)
+    let session = state.session(id)?;
+    let mut ssh = session.ssh.lock().unwrap();
+    ssh.channel_shell_size(channel, cols, rows)
 }
 
 #[tauri::command]
-async fn open_terminal(state: State<'_, AppState>, app: tauri::AppHandle) -> Result<(), String> {
-    let (itx, irx) = std::sync::mpsc::channel();
-    //let (itx, irx) = flume::unbounded();
-    *state.itx.lock().unwrap() = Some(itx);
-    let arcapp = Arc::new(app);
-    let arcappclone = Arc::clone(&arcapp);
-    let mut buf = vec![0; 4096];
+async fn close_terminal(id: u32, channel: u32, state: State<'_, AppState>) -> Result<(), SshError> {
+    let session = state.session(id)?;
+    // dropping the sender ends the writer thread
+    session.itx.lock().unwrap().remove(&channel);
+    let mut ssh = session.ssh.lock().unwrap();
+    ssh.channel_close(channel)
+}
+
+#[tauri::command]
+async fn open_terminal(
+    id: u32,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<u32, SshError> {
+    let session = state.session(id)?;
 
     // create tty shell
-    {
-        let mut ssh = state.ssh.lock().unwrap();
-        ssh.channel_shell().unwrap();
-    }
+    let channel = {
+        let mut ssh = session.ssh.lock().unwrap();
+        ssh.channel_shell()?
+    };
+    let (itx, irx) = std::sync::mpsc::channel();
+    //let (itx, irx) = flume::unbounded();
+    session.itx.lock().unwrap().insert(channel, itx);
 
     // write
     {
-        let ssh = state.ssh.lock().unwrap();
-        let pty = ssh.pty.as_ref().unwrap();
-
-        // env vars
-        // let mut p = pty.lock().unwrap();
-        // p.write(b"export FOO=VAR\n").unwrap();
-        // loop {
-        //     match p.read(&mut buf) {
-        //         Err(e) => {
-        //             if e.kind() == std::io::ErrorKind::WouldBlock {
-        //                 println!("read WouldBlock: {}", e);
-        //                 std::thread::sleep(time::Duration::from_millis(WAIT_MS));
-        //             } else {
-        //                 panic!("Error reading from channel: {:?}", e);
-        //             }
-        //         },
-        //         Ok(n) => {
-        //             if n == 0 {
-        //                 panic!("cannot set env var, exiting.");
-        //             }
-        //             break
-        //         }
-        //     }
-        // }
+        let ssh = session.ssh.lock().unwrap();
+        let pty = ssh.channel_pty(channel)?;
 
         let writer = Arc::clone(&pty);
 
         std::thread::spawn(move || {
-            loop {
-                //println!("{:?}: waiting to recv command...", thread::current().id());
-                if let Ok(cmd) = irx.recv() {
-                    //println!("command: {cmd}");
-                    let mut writer = writer.lock().unwrap();
-
-                    match writer.write(cmd.as_bytes()) {
-                        Ok(0) => {
-                            // Connection closed
-                            println!("Connection closed by server.");
-                            arcapp.exit(1);
+            //println!("{:?}: waiting to recv command...", thread::current().id());
+            while let Ok(cmd) = irx.recv() {
+                //println!("command: {cmd}");
+                let writer = writer.lock().unwrap();
+
+                match writer.write(cmd.as_bytes()) {
+                    Ok(0) => {
+                        // the shell exited, the reader reports it
+                        println!("terminal {channel} closed by server.");
+                        break;
+                    }
+                    Ok(_n) => {
+                        // Process the data
+                        //println!("stdin: \n{}\nend stdin", cmd);
+                        continue;
+                    }
+                    Err(e) => {
+                        if e.kind() == std::io::ErrorKind::WouldBlock {
+                            println!("Write WouldBlock: {}", e);
+                            std::thread::sleep(time::Duration::from_millis(WAIT_MS));
+                            //continue;
+                        } else {
+                            // the connection may be reconnecting, input is dropped
+                            println!("Error writing to channel: {e}");
                         }
-                        Ok(_n) => {
-                            // Process the data
-                            //println!("stdin: \n{}\nend stdin", cmd);
-                            continue;
+                    }
+                }
+            }
+            println!("terminal {channel} writer closed.");
+        });
+    }
+
+    // read, one thread per session serves every channel,
+    // libssh2 may buffer data for any channel when one of them is read
+    if !session.reading.swap(true, Ordering::SeqCst) {
+        let session = Arc::clone(&session);
+        std::thread::spawn(move || {
+            loop {
+                let reason = read_terminals(id, &session, &app);
+                if !reconnect(id, &session, &app, reason) {
+                    break;
+                }
+            }
+            session.reading.store(false, Ordering::SeqCst);
+        });
+    }
+
+    println!("terminal {channel} started.");
+
+    Ok(channel)
+}
+
+// serve the terminals of a session, returns why the connection was lost
+fn read_terminals(id: u32, session: &Session, app: &tauri::AppHandle) -> String {
+    let mut buf = vec![0; 4096];
+    let ptys;
+    let std_tcp;
+    {
+        let lock_ssh = session.ssh.lock().unwrap();
+        ptys = Arc::clone(&lock_ssh.ptys);
+        let tcp = match lock_ssh.tcp.as_ref() {
+            None => return "Not connected".to_string(),
+            Some(o) => o,
+        };
+        let lock_tcp = tcp.lock().unwrap();
+        std_tcp = match lock_tcp.try_clone() {
+            Err(e) => return format!("Cannot clone socket: {e}"),
+            Ok(o) => o,
+        };
+    }
+
+    let mut mio_tcp = TcpStream::from_std(std_tcp);
+    let mut poller = match watch(&mut mio_tcp) {
+        Err(e) => return format!("Cannot poll socket: {e}"),
+        Ok(o) => o,
+    };
+    let mut events = Events::with_capacity(1000);
+
+    loop {
+        //println!("Polling...");
+        if let Err(e) = poller.poll(&mut events, Some(time::Duration::from_millis(POLL_MS))) {
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return format!("Cannot poll socket: {e}");
+        }
+        //println!("Polling: data recieved");
+
+        if events.iter().any(|ev| ev.is_read_closed()) {
+            println!("read closed, connection terminated.");
+            return "Connection closed".to_string();
+        }
+
+        // keep reading all channels until none of them has data, after a timeout too,
+        // transfer and forward threads reading the socket buffer terminal data
+        let mut idle = false;
+        let mut exited = Vec::new();
+        while !idle {
+            idle = true;
+            let channels: Vec<(u32, Arc<Mutex<incoming::RawChannel>>)> = ptys
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(c, p)| (*c, Arc::clone(p)))
+                .collect();
+            for (channel, reader) in channels {
+                let reader = reader.lock().unwrap();
+                loop {
+                    match reader.read(&mut buf) {
+                        Ok(n) => {
+                            if n == 0 {
+                                if reader.eof() && !exited.contains(&channel) {
+                                    exited.push(channel);
+                                }
+                                break;
+                            }
+                            idle = false;
+                            //println!("Stdout: {:?}", &buf[0..n]);
+
+                            let payload = Payload {
+                                id,
+                                channel,
+                                data: buf[..n].to_vec(),
+                            };
+                            if let Err(e) = app.emit("terminal-output", payload) {
+                                println!("{e}");
+                            }
                         }
                         Err(e) => {
                             if e.kind() == std::io::ErrorKind::WouldBlock {
-                                println!("Write WouldBlock: {}", e);
-                                std::thread::sleep(time::Duration::from_millis(WAIT_MS));
-                                //continue;
+                                //println!("blocking reading, trying again");
+                                break;
                             } else {
-                                panic!("Error reading from channel: {:?}", e);
+                                return format!("Cannot read channel: {e}");
                             }
                         }
                     }
                 }
             }
-        });
+        }
+        // the session stays, the user can open another shell
+        for channel in exited {
+            // dropping the sender ends the writer thread
+            session.itx.lock().unwrap().remove(&channel);
+            let pty = session.ssh.lock().unwrap().channel_take(channel);
+            // a slow close must not block the other users of the session
+            let exit = pty.map(|pty| ssh::Ssh::terminal_exit(&pty.lock().unwrap()));
+            match exit {
+                Err(e) => println!("{e}"),
+                Ok(exit) => {
+                    println!("terminal {channel} exited: {exit:?}");
+                    let payload = TerminalExited { id, channel, exit };
+                    if let Err(e) = app.emit("terminal-exited", payload) {
+                        println!("{e}");
+                    }
+                }
+            }
+        }
+        // this must be done in windows
+        if let Err(e) = poller
+            .registry()
+            .reregister(&mut mio_tcp, Token(0), Interest::READABLE)
+        {
+            return format!("Cannot poll socket: {e}");
+        }
     }
+}
 
-    // read
-    {
-        let reader;
-        let std_tcp;
-        {
-            let lock_ssh = state.ssh.lock().unwrap();
-            let pty = lock_ssh.pty.as_ref().unwrap();
-            reader = Arc::clone(pty);
-            let tcp = lock_ssh.tcp.as_ref().unwrap();
-            let lock_tcp = tcp.lock().unwrap();
-            std_tcp = lock_tcp.try_clone().unwrap();
+// a poller for readable events of the session socket
+fn watch(tcp: &mut TcpStream) -> std::io::Result<Poll> {
+    let poller = Poll::new()?;
+    poller
+        .registry()
+        .register(tcp, Token(0), Interest::READABLE)?;
+    Ok(poller)
+}
+
+// reconnect with exponential backoff, false when the session is gone or every attempt failed
+fn reconnect(id: u32, session: &Session, app: &tauri::AppHandle, reason: String) -> bool {
+    println!("session {id} connection lost: {reason}");
+    let mut event = ConnectionEvent {
+        id,
+        attempt: 0,
+        retrying: true,
+        error: reason,
+    };
+    let _ = app.emit("connection-lost", event.clone());
+
+    for attempt in 1..=RECONNECT_ATTEMPTS {
+        let delay = RECONNECT_MAX_SECS.min(1 << (attempt - 1));
+        std::thread::sleep(time::Duration::from_secs(delay));
+        // disconnect removed it meanwhile
+        if app.state::<AppState>().session(id).is_err() {
+            return false;
+        }
+        event.attempt = attempt;
+        let result = session.ssh.lock().unwrap().reconnect();
+        match result {
+            Ok(_) => {
+                println!("session {id} reconnected");
+                event.error.clear();
+                let _ = app.emit("connection-restored", event);
+                return true;
+            }
+            Err(e) => {
+                println!("session {id} reconnect {attempt} failed: {e}");
+                event.error = e.to_string();
+                event.retrying = attempt < RECONNECT_ATTEMPTS;
+                let _ = app.emit("connection-lost", event.clone());
+            }
         }
+    }
+    false
+}
 
-        std::thread::spawn(move || {
-            let mut poller = Poll::new().unwrap();
-            let mut mio_tcp = TcpStream::from_std(std_tcp);
-            poller
-                .registry()
-                .register(&mut mio_tcp, Token(0), Interest::READABLE)
-                .unwrap();
-            let mut events = Events::with_capacity(1000);
-
-            'loop1: loop {
-                //println!("Polling...");
-                poller.poll(&mut events, None).unwrap();
-                //println!("Polling: data recieved");
-
-                let mut reader = reader.lock().unwrap();
-
-                if let Some(ev) = events.iter().next() {
-                    //println!("EVENT: {:?}", ev);
-                    if ev.is_read_closed() {
-                        println!("read closed, connection terminated.");
-                        arcappclone.exit(1);
-                        break 'loop1;
+// send keepalives while the session exists, like openssh ServerAliveInterval anything
+// read from the server counts as a reply, after count_max intervals without one the
+// socket is shut down and the connection is reestablished
+fn keepalive(id: u32, session: Arc<Session>, app: tauri::AppHandle) {
+    let (interval, count_max) = session.ssh.lock().unwrap().keepalive();
+    if interval == 0 {
+        return;
+    }
+    std::thread::spawn(move || {
+        let mut events = Events::with_capacity(16);
+        // the watched socket, a reconnect replaces it
+        let mut watched: Option<(Arc<Mutex<std::net::TcpStream>>, Poll, TcpStream)> = None;
+        let mut missed = 0;
+        let mut dead = false;
+        while app.state::<AppState>().session(id).is_ok() {
+            let tcp = session.ssh.lock().unwrap().tcp.clone();
+            let tcp = match tcp {
+                None => {
+                    std::thread::sleep(time::Duration::from_secs(1));
+                    continue;
+                }
+                Some(o) => o,
+            };
+            let same = watched.as_ref().is_some_and(|(t, _, _)| Arc::ptr_eq(t, &tcp));
+            // wait for the reconnect
+            if dead && same {
+                std::thread::sleep(time::Duration::from_secs(1));
+                continue;
+            }
+            if !same {
+                let std_tcp = match tcp.lock().unwrap().try_clone() {
+                    Err(e) => {
+                        println!("keepalive: {e}");
+                        return;
                     }
-
-                    if ev.token() == Token(0) {
-                        loop {
-                            match reader.read(&mut buf) {
-                                Ok(n) => {
-                                    if n == 0 {
-                                        println!("read is ZERO, exiting...");
-                                        reader.close().unwrap();
-                                        arcappclone.exit(1);
-                                        break 'loop1;
-                                    }
-                                    //println!("Stdout: {:?}", &buf[0..n]);
-                                    // let data = match String::from_utf8(buf[..n].to_vec()) {
-                                    //      Ok(o) => o,
-                                    //      Err(e) => panic!("invalid utf-8 sequence: {}", e)
-                                    // };
-                                    // println!("result ({n}):\n{}", data);
-
-                                    arcappclone
-                                        .emit(
-                                            "terminal-output",
-                                            Payload {
-                                                data: buf[..n].to_vec(),
-                                            },
-                                        )
-                                        .unwrap();
-
-                                    // let chunk_size = 1000;
-                                    // let total_chunks = (buf.len() + chunk_size - 1) / chunk_size;
-
-                                    // for i in 0..total_chunks {
-                                    //     let start = i * chunk_size;
-                                    //     let end = std::cmp::min(start + chunk_size, buf.len());
-                                    //     println!("chunk {} {} {}", i, start, end);
-                                    //     app.emit("terminal-output", Payload {data: buf[start..end].to_vec()}).unwrap();
-
-                                    // }
-                                }
-                                Err(e) => {
-                                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                                        //println!("blocking reading, trying again");
-                                        break;
-                                        //tokio::time::sleep(time::Duration::from_millis(WAIT_MS)).await;
-                                        // TODO:
-                                        // poll_for_new_data();
-                                    } else {
-                                        panic!("Cannot read channel: {e}");
-                                    }
-                                }
-                            }
-                        }
-                        // this must be done in windows
-                        poller
-                            .registry()
-                            .reregister(&mut mio_tcp, Token(0), Interest::READABLE)
-                            .unwrap();
+                    Ok(o) => o,
+                };
+                let mut mio_tcp = TcpStream::from_std(std_tcp);
+                let poller = match watch(&mut mio_tcp) {
+                    Err(e) => {
+                        println!("keepalive: {e}");
+                        return;
                     }
+                    Ok(o) => o,
                 };
+                watched = Some((tcp, poller, mio_tcp));
+                missed = 0;
+                dead = false;
             }
-        });
-    }
+            let (tcp, poller, mio_tcp) = match watched.as_mut() {
+                None => continue,
+                Some(o) => o,
+            };
 
-    println!("terminal started.");
+            let timeout = time::Duration::from_secs(interval as u64);
+            if poller.poll(&mut events, Some(timeout)).is_err() || !events.is_empty() {
+                missed = 0;
+                // this must be done in windows
+                let _ = poller
+                    .registry()
+                    .reregister(mio_tcp, Token(0), Interest::READABLE);
+                continue;
+            }
+            missed += 1;
+            if missed <= count_max {
+                if let Err(e) = session.ssh.lock().unwrap().keepalive_send() {
+                    println!("keepalive: {e}");
+                }
+                continue;
+            }
 
-    Ok(())
+            println!("session {id} connection dead after {count_max} keepalives");
+            let _ = app.emit("connection-dead", DeadConnection { id, missed: count_max });
+            let _ = tcp.lock().unwrap().shutdown(std::net::Shutdown::Both);
+            // without terminals nobody reads the socket, reconnect here
+            if !session.reading.swap(true, Ordering::SeqCst) {
+                let reason = "Keepalive timeout".to_string();
+                reconnect(id, &session, &app, reason);
+                session.reading.store(false, Ordering::SeqCst);
+            }
+            dead = true;
+        }
+    });
 }
 
 #[tokio::main]
@@ -386,16 +1260,49 @@ async fn main() {
         .invoke_handler(tauri::generate_handler![
             read_settings,
             write_settings,
+            parse_proxy_jump,
+            connect,
             connect_with_key,
+            connect_with_agent,
+            connect_with_keyboard_interactive,
             connect_with_password,
+            confirm_host_key,
+            keyboard_interactive_response,
             ssh_run,
             download,
             upload,
             setup_ssh,
             disconnect,
             open_terminal,
+            close_terminal,
             send_key,
             resize,
+            add_local_forward,
+            add_remote_forward,
+            start_socks_proxy,
+            list_forwards,
+            remove_forward,
+            sftp_stat,
+            sftp_readdir,
+            sftp_mkdir,
+            sftp_rmdir,
+            sftp_create,
+            sftp_rename,
+            sftp_delete,
+            sftp_readlink,
+            sftp_realpath,
+            sftp_save,
+            queue_download,
+            queue_upload,
+            queue_download_dir,
+            queue_upload_dir,
+            list_transfers,
+            pause_transfer,
+            resume_transfer,
+            cancel_transfer,
+            retry_transfer,
+            clear_transfers,
+            set_transfer_concurrency,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,169 @@
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// at most one transfer-progress event per interval, the last one is always sent
+const INTERVAL_MS: u64 = 250;
+
+// weight of the newest measurement in the smoothed rate
+const RATE_WEIGHT: f64 = 0.3;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct TransferProgress {
+    pub transfer: u32,
+    pub bytes: u64,
+    pub total: u64,
+    // bytes per second
+    pub rate: u64,
+    // seconds left, None until the rate is known
+    pub eta: Option<u64>,
+    // directory transfers count their files, 0 for a single file
+    pub files: u32,
+    pub files_done: u32,
+}
+
+pub type Report = dyn FnMut(TransferProgress) + Send;
+
+// counts the bytes of one transfer and reports them throttled
+pub struct Progress {
+    transfer: u32,
+    total: u64,
+    bytes: u64,
+    rate: f64,
+    started: Instant,
+    reported: Instant,
+    reported_bytes: u64,
+    files: u32,
+    files_done: u32,
+    report: Box<Report>,
+}
+
+impl Progress {
+    pub fn new(transfer: u32, report: impl FnMut(TransferProgress) + Send + 'static) -> Self {
+        let now = Instant::now();
+        Self {
+            transfer,
+            total: 0,
+            bytes: 0,
+            rate: 0.,
+            started: now,
+            reported: now,
+            reported_bytes: 0,
+            files: 0,
+            files_done: 0,
+            report: Box::new(report),
+        }
+    }
+    pub fn transfer(&self) -> u32 {
+        self.transfer
+    }
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+    // the size, once it is known, and the bytes already there for a resumed transfer
+    pub fn start(&mut self, total: u64, bytes: u64) {
+        self.total = total;
+        self.bytes = bytes;
+        self.reported_bytes = bytes;
+        self.send(Instant::now());
+    }
+    pub fn set_files(&mut self, files: u32) {
+        self.files = files;
+    }
+    pub fn file_done(&mut self) {
+        self.files_done += 1;
+    }
+    pub fn add(&mut self, n: usize) {
+        self.add_at(n, Instant::now());
+    }
+    fn add_at(&mut self, n: usize, now: Instant) {
+        self.bytes += n as u64;
+        if now.duration_since(self.reported) >= Duration::from_millis(INTERVAL_MS) {
+            self.send(now);
+        }
+    }
+    // the last event, unless nothing changed since the previous one
+    pub fn finish(&mut self) {
+        if self.bytes != self.reported_bytes {
+            self.send(Instant::now());
+        }
+    }
+    fn send(&mut self, now: Instant) {
+        let secs = now.duration_since(self.reported).as_secs_f64();
+        if secs > 0. {
+            let rate = (self.bytes - self.reported_bytes) as f64 / secs;
+            self.rate = if self.rate == 0. {
+                rate
+            } else {
+                RATE_WEIGHT * rate + (1. - RATE_WEIGHT) * self.rate
+            };
+        }
+        self.reported = now;
+        self.reported_bytes = self.bytes;
+        let left = self.total.saturating_sub(self.bytes);
+        let eta = if self.rate >= 1. {
+            Some((left as f64 / self.rate).ceil() as u64)
+        } else {
+            None
+        };
+        (self.report)(TransferProgress {
+            transfer: self.transfer,
+            bytes: self.bytes,
+            total: self.total,
+            rate: self.rate as u64,
+            eta,
+            files: self.files,
+            files_done: self.files_done,
+        });
+    }
+}
+
+// a progress keeping every report, for the tests of the transfers
+#[cfg(test)]
+pub fn recorded(transfer: u32) -> (Progress, Arc<Mutex<Vec<TransferProgress>>>) {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sent = events.clone();
+    let progress = Progress::new(transfer, move |p| sent.lock().unwrap().push(p));
+    (progress, events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttled() {
+        let (mut progress, events) = recorded(7);
+        progress.start(1000, 0);
+        let start = progress.reported;
+        for ms in 1..10 {
+            progress.add_at(10, start + Duration::from_millis(ms));
+        }
+        assert_eq!(events.lock().unwrap().len(), 1);
+        progress.add_at(10, start + Duration::from_millis(INTERVAL_MS));
+        progress.add_at(10, start + Duration::from_millis(INTERVAL_MS + 1));
+        progress.finish();
+        progress.finish();
+        let events = events.lock().unwrap();
+        let bytes: Vec<u64> = events.iter().map(|e| e.bytes).collect();
+        assert_eq!(bytes, vec![0, 100, 110]);
+        assert!(events.iter().all(|e| e.transfer == 7 && e.total == 1000));
+    }
+
+    #[test]
+    fn rate_and_eta() {
+        let (mut progress, events) = recorded(7);
+        progress.start(10_000, 1000);
+        assert_eq!(events.lock().unwrap()[0].eta, None);
+        let start = progress.reported;
+        progress.add_at(1000, start + Duration::from_secs(1));
+        progress.add_at(2000, start + Duration::from_secs(2));
+        let events = events.lock().unwrap();
+        assert_eq!((events[1].bytes, events[1].rate, events[1].eta), (2000, 1000, Some(8)));
+        // smoothed towards the faster second
+        assert_eq!((events[2].bytes, events[2].rate, events[2].eta), (4000, 1300, Some(5)));
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter};
+
+// how long a connection waits for the user before giving up
+const PROMPT_TIMEOUT_SECS: u64 = 300;
+
+// answers are None when the user cancels
+type Answer = Option<Vec<String>>;
+
+#[derive(Clone, serde::Serialize)]
+struct Payload<T> {
+    prompt: u32,
+    #[serde(flatten)]
+    question: T,
+}
+
+// questions sent to the frontend as events, waiting for an answer command
+#[derive(Clone, Default)]
+pub struct Prompts {
+    pending: Arc<Mutex<HashMap<u32, Sender<Answer>>>>,
+    next_id: Arc<AtomicU32>,
+}
+
+impl Prompts {
+    // blocks until answer() is called with the prompt id sent in the event
+    pub fn ask<T: serde::Serialize + Clone>(
+        &self,
+        app: &AppHandle,
+        event: &str,
+        question: T,
+    ) -> Result<Answer, String> {
+        let prompt = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.pending.lock().unwrap().insert(prompt, tx);
+
+        if let Err(e) = app.emit(event, Payload { prompt, question }) {
+            self.pending.lock().unwrap().remove(&prompt);
+            return Err(format!("Cannot send {event}: {e}"));
+        }
+        let answer = rx.recv_timeout(Duration::from_secs(PROMPT_TIMEOUT_SECS));
+        self.pending.lock().unwrap().remove(&prompt);
+        answer.map_err(|_| format!("No answer to {event}"))
+    }
+    pub fn answer(&self, prompt: u32, answer: Answer) -> Result<(), String> {
+        match self.pending.lock().unwrap().remove(&prompt) {
+            None => Err(format!("Prompt {prompt} not found")),
+            Some(tx) => tx.send(answer).map_err(|e| e.to_string()),
+        }
+    }
+}
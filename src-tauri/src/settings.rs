@@ -1,8 +1,9 @@
 use confy;
 use dirs;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Settings {
     pub server: String,
     pub user: String,
@@ -14,6 +15,114 @@ pub struct Settings {
 
     #[serde(skip_serializing)]
     pub password: Option<String>,
+
+    #[serde(skip_serializing)]
+    pub passphrase: Option<String>,
+
+    // jump servers in connection order, like ProxyJump a,b,c
+    #[serde(default)]
+    pub jumps: Vec<Jump>,
+
+    // local command to reach the server or the first jump, like ProxyCommand
+    #[serde(default)]
+    pub proxy_command: String,
+
+    // forward remote X11 clients to the local DISPLAY
+    #[serde(default)]
+    pub x11_forwarding: bool,
+
+    // hosts the local agent is forwarded to, like ForwardAgent in a Host block
+    #[serde(default)]
+    pub agent_forwarding: Vec<String>,
+
+    // variables set in every terminal, exported when the server refuses setenv
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
+    // directory the terminals start in and a command run when they open
+    #[serde(default)]
+    pub working_dir: String,
+    #[serde(default)]
+    pub startup_command: String,
+
+    // seconds between keepalives, 0 disables them, and how many may go
+    // unanswered before the connection is dead, 0 means 3
+    #[serde(default)]
+    pub keepalive_interval: u32,
+    #[serde(default)]
+    pub keepalive_count_max: u32,
+}
+
+// bastion the target is reached through, authenticated with the agent,
+// the private key or the password, whichever the jump host accepts
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Jump {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+
+    pub private_key: Option<String>,
+
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+
+    #[serde(skip_serializing)]
+    pub passphrase: Option<String>,
+}
+
+// secrets are printed as set or not, settings are logged when read and written
+fn redacted(secret: &Option<String>) -> Option<&'static str> {
+    secret.as_ref().map(|_| "***")
+}
+
+impl std::fmt::Debug for Settings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Settings")
+            .field("server", &self.server)
+            .field("user", &self.user)
+            .field("port", &self.port)
+            .field("home_dir", &self.home_dir)
+            .field("private_key", &self.private_key)
+            .field("password", &redacted(&self.password))
+            .field("passphrase", &redacted(&self.passphrase))
+            .field("jumps", &self.jumps)
+            .field("proxy_command", &self.proxy_command)
+            .field("x11_forwarding", &self.x11_forwarding)
+            .field("agent_forwarding", &self.agent_forwarding)
+            .field("env", &self.env)
+            .field("working_dir", &self.working_dir)
+            .field("startup_command", &self.startup_command)
+            .field("keepalive_interval", &self.keepalive_interval)
+            .field("keepalive_count_max", &self.keepalive_count_max)
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for Jump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Jump")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("user", &self.user)
+            .field("private_key", &self.private_key)
+            .field("password", &redacted(&self.password))
+            .field("passphrase", &redacted(&self.passphrase))
+            .finish()
+    }
+}
+
+impl Default for Jump {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            port: 22,
+            user: String::new(),
+            private_key: None,
+            password: None,
+            passphrase: None,
+        }
+    }
 }
 
 impl Default for Settings {
@@ -32,8 +141,81 @@ impl Default for Settings {
             port: 22,
             home_dir: home,
             private_key: Some(pkey),
+            passphrase: None,
+            jumps: Vec::new(),
+            proxy_command: String::new(),
+            x11_forwarding: false,
+            agent_forwarding: Vec::new(),
+            env: BTreeMap::new(),
+            working_dir: String::new(),
+            startup_command: String::new(),
+            keepalive_interval: 0,
+            keepalive_count_max: 3,
+        }
+    }
+}
+
+impl Settings {
+    pub fn forwards_agent(&self) -> bool {
+        self.agent_forwarding
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(&self.server))
+    }
+}
+
+// letters, digits and underscores, not starting with a digit
+pub fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+// parse an openssh ProxyJump value, [user@]host[:port] hops separated by commas,
+// hops without a user log in as default_user
+pub fn parse_proxy_jump(spec: &str, default_user: &str) -> Result<Vec<Jump>, String> {
+    let mut jumps = Vec::new();
+    for hop in spec.split(',').map(str::trim).filter(|h| !h.is_empty()) {
+        let (user, address) = match hop.rsplit_once('@') {
+            None => (default_user, hop),
+            Some(o) => o,
+        };
+        // ipv6 addresses are written in brackets, [::1]:2222
+        let (host, port) = match address.strip_prefix('[') {
+            Some(rest) => match rest.split_once(']') {
+                None => return Err(format!("Invalid jump host: {hop}")),
+                Some((host, "")) => (host, None),
+                Some((host, port)) => match port.strip_prefix(':') {
+                    None => return Err(format!("Invalid jump host: {hop}")),
+                    Some(port) => (host, Some(port)),
+                },
+            },
+            None => match address.split_once(':') {
+                None => (address, None),
+                Some((host, port)) => (host, Some(port)),
+            },
+        };
+        let port = match port {
+            None => 22,
+            Some(port) => match port.parse() {
+                Err(_) => return Err(format!("Invalid jump port: {hop}")),
+                Ok(o) => o,
+            },
+        };
+        if host.is_empty() || user.is_empty() {
+            return Err(format!("Invalid jump host: {hop}"));
         }
+        jumps.push(Jump {
+            host: host.to_string(),
+            port,
+            user: user.to_string(),
+            ..Default::default()
+        });
     }
+    Ok(jumps)
 }
 
 pub fn read_settings() -> Result<Settings, String> {
@@ -64,3 +246,66 @@ pub fn write_settings(settings: Settings) -> Result<(), String> {
         Ok(_) => Ok(()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_jump_chain() {
+        let jumps = parse_proxy_jump("bastion, ops@gw:2222,[fe80::1]:2200", "support").unwrap();
+        assert_eq!(jumps.len(), 3);
+        assert_eq!((jumps[0].host.as_str(), jumps[0].port), ("bastion", 22));
+        assert_eq!(jumps[0].user, "support");
+        assert_eq!((jumps[1].host.as_str(), jumps[1].port), ("gw", 2222));
+        assert_eq!(jumps[1].user, "ops");
+        assert_eq!((jumps[2].host.as_str(), jumps[2].port), ("fe80::1", 2200));
+        assert!(parse_proxy_jump("", "support").unwrap().is_empty());
+    }
+
+    #[test]
+    fn proxy_jump_invalid() {
+        assert!(parse_proxy_jump("gw:port", "support").is_err());
+        assert!(parse_proxy_jump("[::1", "support").is_err());
+        assert!(parse_proxy_jump("ops@:22", "support").is_err());
+    }
+
+    #[test]
+    fn agent_forwarding_hosts() {
+        let mut settings = Settings {
+            server: "Bastion".into(),
+            ..Default::default()
+        };
+        assert!(!settings.forwards_agent());
+        settings.agent_forwarding = vec!["gw".into(), "bastion".into()];
+        assert!(settings.forwards_agent());
+    }
+
+    #[test]
+    fn env_names() {
+        assert!(is_env_name("LANG"));
+        assert!(is_env_name("_APP_ENV2"));
+        assert!(!is_env_name(""));
+        assert!(!is_env_name("2FA"));
+        assert!(!is_env_name("A;rm"));
+    }
+
+    #[test]
+    fn secrets_not_printed() {
+        let settings = Settings {
+            password: Some("hunter2".into()),
+            passphrase: Some("open sesame".into()),
+            jumps: vec![Jump {
+                host: "gw".into(),
+                password: Some("letmein".into()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let printed = format!("{settings:?}");
+        assert!(printed.contains("\"gw\""), "{printed}");
+        for secret in ["hunter2", "open sesame", "letmein"] {
+            assert!(!printed.contains(secret), "{printed}");
+        }
+    }
+}
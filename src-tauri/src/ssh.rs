@@ -1,35 +1,86 @@
-use ssh2::{Channel, FileStat, Session, Sftp};
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ssh2::{FileStat, Session, Sftp};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
 use std::net::{TcpStream, ToSocketAddrs};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{thread, time};
 
+use super::agent::{self, AgentForward, LocalAgent};
+use super::auth::{self, AuthMethod, Challenge, ChallengePrompt};
 use super::command;
+use super::error::SshError;
+use super::files::{self, FileEntry, FileKind};
+use super::forward::{self, Forward, ForwardInfo};
+use super::incoming::{self, RawChannel};
+use super::known_hosts::{self, HostKey, HostKeyPrompt};
+use super::settings::{is_env_name, Jump};
+use super::tunnel;
+use super::x11::{self, X11};
 
 const WAIT_MS: u64 = 20;
 
+// how long an ended terminal may take to close, its exit status comes before
+const CLOSE_TIMEOUT_MS: u64 = 2000;
+
+// keyboard-interactive tries before giving up, like openssh NumberOfPasswordPrompts
+const KBD_ATTEMPTS: u32 = 3;
+
+// error returned when the private key is encrypted and no passphrase was given
+pub const PASSPHRASE_REQUIRED: &str = "Passphrase required";
+
+// how a terminal shell ended, servers send the status or the signal before closing
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct TerminalExit {
+    pub status: Option<i32>,
+    pub signal: Option<String>,
+    pub message: Option<String>,
+}
+
 #[derive(Default)]
 pub struct Ssh {
     pub session: Option<Session>,
     pub tcp: Option<Arc<Mutex<TcpStream>>>,
-    pub pty: Option<Arc<Mutex<Channel>>>,
+    pub ptys: Arc<Mutex<HashMap<u32, Arc<Mutex<RawChannel>>>>>,
+    next_pty: u32,
+    forwards: HashMap<u32, Forward>,
+    next_forward: u32,
     sftp: Option<Sftp>,
     host: String,
+    port: u16,
     user: String,
     password: String,
     private_key: String,
-
-    // Add jump server connection info
-    pub jump_session: Option<Session>,
-    pub jump_tcp: Option<Arc<Mutex<TcpStream>>>,
-    pub tunnel_channel: Option<Channel>,
-    jump_host: String,
-    jump_user: String,
-    jump_password: String,
-    jump_private_key: String,
+    passphrase: Option<String>,
+    // the method that worked, reconnect authenticates the same way
+    auth: Option<AuthMethod>,
+    // last terminal sizes, reopened terminals get them back
+    sizes: HashMap<u32, (u32, u32)>,
+    // seconds between keepalives, 0 is off, and unanswered ones before giving up
+    keepalive_interval: u32,
+    keepalive_count_max: u32,
+
+    // jump server chain, like ProxyJump a,b,c the target session runs through every hop
+    pub jump_sessions: Vec<Session>,
+    jumps: Vec<Jump>,
+    // local command the first connection runs through, like ProxyCommand
+    proxy_command: String,
+    x11_forwarding: bool,
+    x11: Option<X11>,
+    agent_forwarding: bool,
+    agent: Option<AgentForward>,
+    // local agent socket, SSH_AUTH_SOCK or pageant when None
+    agent_path: Option<String>,
+    // terminal environment, start directory and command
+    env: BTreeMap<String, String>,
+    working_dir: String,
+    startup_command: String,
+
+    host_key_prompt: Option<Box<HostKeyPrompt>>,
+    challenge_prompt: Option<Box<ChallengePrompt>>,
 }
 
 // #[derive(Clone, serde::Serialize)]
@@ -79,13 +130,22 @@ impl Ssh {
         let pubkey = home.join(".ssh").join("id_rsa.pub").clone();
         PathBuf::from(&pubkey)
     }
+    // identities tried by default, in the order openssh uses
+    pub fn default_identities() -> Vec<PathBuf> {
+        let home = dirs::home_dir().unwrap();
+        ["id_ed25519", "id_ecdsa", "id_rsa"]
+            .iter()
+            .map(|k| home.join(".ssh").join(k))
+            .filter(|k| k.exists())
+            .collect()
+    }
     pub fn has_private_key() -> bool {
         Ssh::private_key_path().exists()
     }
     pub fn has_public_key() -> bool {
         Ssh::public_key_path().exists()
     }
-    fn generate_public_key() -> Result<(), String> {
+    fn generate_public_key() -> Result<(), SshError> {
         let seckey = Ssh::private_key_path();
         let pubkey = Ssh::public_key_path();
 
@@ -97,19 +157,19 @@ impl Ssh {
         let (_, e, _) = command::run(&cmd);
 
         if e.len() > 0 {
-            Err(e)
+            Err(SshError::Io(e))
         } else {
             Ok(())
         }
     }
-    fn generate_keys() -> Result<(), String> {
+    fn generate_keys() -> Result<(), SshError> {
         let seckey = Ssh::private_key_path();
 
         let cmd = format!("ssh-keygen -m PEM -N \"\" -f {}", seckey.display());
         let (_, e, _) = command::run(&cmd);
 
         if e.len() > 0 {
-            Err(e)
+            Err(SshError::Io(e))
         } else {
             Ok(())
         }
@@ -119,9 +179,9 @@ impl Ssh {
         port: u16,
         user: &str,
         password: &str,
-    ) -> Result<(), String> {
-        let pubkeytext = std::fs::read_to_string(&Ssh::public_key_path())
-            .unwrap()
+        prompt: impl Fn(&HostKey) -> bool + Send + 'static,
+    ) -> Result<(), SshError> {
+        let pubkeytext = std::fs::read_to_string(&Ssh::public_key_path())?
             .trim()
             .to_string();
         let cmd = format!(
@@ -130,6 +190,7 @@ impl Ssh {
         );
         println!("{cmd}");
         let mut ssh = Ssh::new();
+        ssh.set_host_key_prompt(prompt);
         if let Err(e) = ssh.connect_with_password(host, port, user, password).await {
             println!("Error transfering keys, login with password: {e}");
             return Err(e);
@@ -141,14 +202,20 @@ impl Ssh {
             Ok(())
         }
     }
-    async fn test_ssh(host: &str, port: u16, user: &str) -> Result<(), String> {
+    async fn test_ssh(
+        host: &str,
+        port: u16,
+        user: &str,
+        prompt: impl Fn(&HostKey) -> bool + Send + 'static,
+    ) -> Result<(), SshError> {
         if !Ssh::has_private_key() {
-            return Err("No private key".to_string());
+            return Err(SshError::Auth("No private key".to_string()));
         }
         let pkey = Ssh::private_key_path();
         let mut ssh = Ssh::new();
+        ssh.set_host_key_prompt(prompt);
         if let Err(e) = ssh
-            .connect_with_key(host, port, user, pkey.to_str().unwrap())
+            .connect_with_key(host, port, user, &pkey.to_string_lossy(), None)
             .await
         {
             Err(e)
@@ -156,38 +223,54 @@ impl Ssh {
             Ok(())
         }
     }
+    // the host key is checked like on connect, the prompt is used by every connection
     pub async fn setup_ssh(
         host: &str,
         port: u16,
         user: &str,
         password: &str,
-    ) -> Result<(), String> {
+        prompt: impl Fn(&HostKey) -> bool + Clone + Send + 'static,
+    ) -> Result<(), SshError> {
         if !Ssh::has_private_key() {
             if let Err(e) = Ssh::generate_keys() {
-                return Err(format!("Could not generate private key: {e}"));
+                return Err(e.map_message(|e| format!("Could not generate private key: {e}")));
             }
         }
         if !Ssh::has_public_key() {
             if let Err(e) = Ssh::generate_public_key() {
-                return Err(format!("Could not generate public key: {e}"));
+                return Err(e.map_message(|e| format!("Could not generate public key: {e}")));
             }
         }
-        if Ssh::test_ssh(host, port, user).await.is_err() {
-            if let Err(e) = Ssh::transfer_public_key(host, port, user, password).await {
-                return Err(format!("Could not transfer public key: {e}"));
+        if Ssh::test_ssh(host, port, user, prompt.clone()).await.is_err() {
+            if let Err(e) = Ssh::transfer_public_key(host, port, user, password, prompt.clone()).await {
+                return Err(e.map_message(|e| format!("Could not transfer public key: {e}")));
             }
-            if let Err(e) = Ssh::test_ssh(host, port, user).await {
-                return Err(format!("Test ssh failed: {e}"));
+            if let Err(e) = Ssh::test_ssh(host, port, user, prompt).await {
+                return Err(e.map_message(|e| format!("Test ssh failed: {e}")));
             }
         }
         Ok(())
     }
-    fn _get_tcp(&mut self, host: &str, port: u16) -> Result<TcpStream, String> {
+    pub fn set_host_key_prompt(&mut self, prompt: impl Fn(&HostKey) -> bool + Send + 'static) {
+        self.host_key_prompt = Some(Box::new(prompt));
+    }
+    pub fn set_challenge_prompt(
+        &mut self,
+        prompt: impl Fn(&Challenge) -> Option<Vec<String>> + Send + 'static,
+    ) {
+        self.challenge_prompt = Some(Box::new(prompt));
+    }
+    fn verify_host_key(&self, session: &Session, host: &str, port: u16) -> Result<(), SshError> {
+        let path = known_hosts::known_hosts_path();
+        known_hosts::verify(session, host, port, &path, self.host_key_prompt.as_deref())?;
+        Ok(())
+    }
+    fn _get_tcp(&mut self, host: &str, port: u16) -> Result<TcpStream, SshError> {
         let timeout = Duration::new(5, 0); // 5 secs
         let addresses: Vec<_> = match format!("{}:{}", host, port).to_socket_addrs() {
             Err(e) => {
                 println!("Unable to resolve address: {}:{}  {:?}", host, port, e);
-                return Err(e.to_string());
+                return Err(SshError::Connect(e.to_string()));
             }
             Ok(o) => o.collect(),
         };
@@ -209,65 +292,194 @@ impl Ssh {
             };
         }
 
-        if tcp.is_none() {
-            return Err(error);
+        let tcp = match tcp {
+            None => return Err(SshError::Connect(error)),
+            Some(o) => o,
+        };
+        if let Err(e) = tcp.set_nonblocking(true) {
+            return Err(SshError::Connect(format!("Cannot set socket non-blocking: {e}")));
         }
 
-        let tcp = tcp.unwrap();
-        tcp.set_nonblocking(true).unwrap();
-
         Ok(tcp)
     }
-    pub async fn connect_with_password(
+    // direct tcp connection, or the socket of the proxy command when configured
+    fn _get_proxy_tcp(&mut self, host: &str, port: u16) -> Result<TcpStream, SshError> {
+        if self.proxy_command.is_empty() {
+            return self._get_tcp(host, port);
+        }
+        let cmd = command::expand_proxy_command(&self.proxy_command, host, port);
+        println!("connecting to {host}:{port} with proxy command: {cmd}");
+        let tcp = command::proxy(&cmd).map_err(SshError::Connect)?;
+        if let Err(e) = tcp.set_nonblocking(true) {
+            return Err(SshError::Connect(format!("Cannot set proxy socket non-blocking: {e}")));
+        }
+        Ok(tcp)
+    }
+    // ssh handshake on a connected socket, the host key is verified before any auth
+    fn _handshake(&self, tcp: &TcpStream, host: &str, port: u16) -> Result<Session, SshError> {
+        let tcp_clone = match tcp.try_clone() {
+            Err(e) => return Err(SshError::Connect(format!("Cannot clone tcp stream: {e}"))),
+            Ok(o) => o,
+        };
+        let mut session = match Session::new() {
+            Err(e) => return Err(SshError::Connect(format!("Cannot create ssh session: {e}"))),
+            Ok(o) => o,
+        };
+        session.set_tcp_stream(tcp_clone);
+
+        if let Err(e) = session.handshake() {
+            return Err(SshError::Connect(format!("SSH handshake error: {}", e)));
+        }
+        self.verify_host_key(&session, host, port)?;
+        Ok(session)
+    }
+    // tcp connection to the host, or through the jump servers when configured
+    fn _get_session(
         &mut self,
         host: &str,
         port: u16,
-        user: &str,
-        password: &str,
-    ) -> Result<(), String> {
-        let tcp = match self._get_tcp(host, port) {
-            Err(e) => return Err(e),
-            Ok(o) => o,
+    ) -> Result<(Arc<Mutex<TcpStream>>, Session), SshError> {
+        let tcp = if self.jumps.is_empty() {
+            self._get_proxy_tcp(host, port)?
+        } else {
+            self._get_tcp_via_jumps(host, port, &self.jumps.clone())?
+        };
+        let session = self._handshake(&tcp, host, port)?;
+        Ok((Arc::new(Mutex::new(tcp)), session))
+    }
+    // log in to every hop in order, each one through a direct-tcpip channel
+    // of the previous hop, the last channel goes to the target
+    fn _get_tcp_via_jumps(
+        &mut self,
+        host: &str,
+        port: u16,
+        jumps: &[Jump],
+    ) -> Result<TcpStream, SshError> {
+        // errors name the hop that failed
+        let hop = |i: usize| {
+            let jump = &jumps[i];
+            format!("Jump server {} ({}:{})", i + 1, jump.host, jump.port)
         };
 
-        // Check if jump server is configured
-        if !self.jump_host.is_empty() {
-            return self.connect_with_password_via_jump(host, port, user, password).await;
+        let mut tcp = match self._get_proxy_tcp(&jumps[0].host, jumps[0].port) {
+            Err(e) => return Err(e.map_message(|e| format!("{} connection failed: {e}", hop(0)))),
+            Ok(o) => o,
+        };
+        for (i, jump) in jumps.iter().enumerate() {
+            let (next_host, next_port) = match jumps.get(i + 1) {
+                None => (host, port),
+                Some(next) => (next.host.as_str(), next.port),
+            };
+            tcp = match self._get_tcp_via_jump(tcp, jump, next_host, next_port) {
+                Err(e) => return Err(e.map_message(|e| format!("{} {e}", hop(i)))),
+                Ok(o) => o,
+            };
         }
-
-        // clone tcp instance
-        let arc_tcp = Arc::new(Mutex::new(tcp));
-        let tcp_clone;
-        {
-            let locked_tcp = arc_tcp.lock().unwrap();
-            tcp_clone = locked_tcp.try_clone().unwrap();
+        Ok(tcp)
+    }
+    // log in to one jump server and open a direct-tcpip channel to the next host,
+    // the channel is pumped to a loopback socket the next session runs on
+    fn _get_tcp_via_jump(
+        &mut self,
+        jump_tcp: TcpStream,
+        jump: &Jump,
+        host: &str,
+        port: u16,
+    ) -> Result<TcpStream, SshError> {
+        let jump_session = self._handshake(&jump_tcp, &jump.host, jump.port)?;
+
+        let keys: Vec<String> = jump
+            .private_key
+            .iter()
+            .filter(|k| !k.is_empty())
+            .cloned()
+            .collect();
+        let password = jump.password.as_deref().filter(|p| !p.is_empty());
+        match self.authenticate(
+            &jump_session,
+            &jump.user,
+            &keys,
+            jump.passphrase.as_deref(),
+            password,
+        ) {
+            Err(e) => return Err(e.map_message(|e| format!("authentication error: {e}"))),
+            Ok(m) => println!("jump server {} authenticated with: {:?}", jump.host, m),
         }
 
-        let mut session = Session::new().unwrap();
-        session.set_tcp_stream(tcp_clone);
+        let channel = match jump_session.channel_direct_tcpip(host, port, None) {
+            Err(e) => {
+                let e = format!("failed to create tunnel to {host}:{port}: {e}");
+                return Err(SshError::Channel(e));
+            }
+            Ok(o) => o,
+        };
+        jump_session.set_blocking(false);
 
-        if let Err(e) = session.handshake() {
-            return Err(format!("SSH handshake error: {}", e));
-        }
+        let (local, remote) = match tunnel::local_pair() {
+            Err(e) => return Err(SshError::Io(format!("cannot create tunnel socket: {e}"))),
+            Ok(o) => o,
+        };
+        let target = format!("{host}:{port}");
+        thread::spawn(move || {
+            let traffic = tunnel::Traffic::default();
+            match tunnel::pump(channel, remote, jump_tcp, &traffic) {
+                Err(e) => println!("tunnel to {target} closed: {e}"),
+                Ok(_) => println!("tunnel to {target} closed"),
+            }
+        });
 
-        if let Err(e) = session.userauth_password(user, password) {
-            return Err(format!("Authentication error: {e}"));
+        self.jump_sessions.push(jump_session);
+        if let Err(e) = local.set_nonblocking(true) {
+            return Err(SshError::Io(format!("cannot set tunnel socket non-blocking: {e}")));
         }
-
+        Ok(local)
+    }
+    // keep the authenticated session, later calls run non-blocking
+    fn _set_session(
+        &mut self,
+        tcp: Arc<Mutex<TcpStream>>,
+        session: Session,
+        host: &str,
+        port: u16,
+        user: &str,
+    ) -> Result<(), SshError> {
         assert!(session.authenticated());
         let sftp = match session.sftp() {
-            Err(e) => return Err(format!("Cannot create sftp channel {e}")),
+            Err(e) => return Err(SshError::Sftp(format!("Cannot create sftp channel {e}"))),
             Ok(o) => o,
         };
 
         session.set_blocking(false);
+        if self.keepalive_interval > 0 {
+            session.set_keepalive(true, self.keepalive_interval);
+        }
 
-        self.tcp = Some(arc_tcp);
+        self.tcp = Some(tcp);
         self.session = Some(session);
         self.sftp = Some(sftp);
         self.host = host.to_string();
+        self.port = port;
         self.user = user.to_string();
+        Ok(())
+    }
+    pub async fn connect_with_password(
+        &mut self,
+        host: &str,
+        port: u16,
+        user: &str,
+        password: &str,
+    ) -> Result<(), SshError> {
+        let (tcp, session) = self._get_session(host, port)?;
+
+        let methods = Ssh::auth_methods(&session, user);
+        let r = session
+            .userauth_password(user, password)
+            .map_err(|e| SshError::Auth(format!("Authentication error: {e}")));
+        self.userauth_continue(&session, user, Some(password), &methods, r)?;
+
+        self._set_session(tcp, session, host, port, user)?;
         self.password = password.to_string();
+        self.auth = Some(AuthMethod::Password);
         Ok(())
     }
     pub async fn connect_with_key(
@@ -276,161 +488,469 @@ impl Ssh {
         port: u16,
         user: &str,
         pkey: &str,
-    ) -> Result<(), String> {
-        let tcp = match self._get_tcp(host, port) {
-            Err(e) => return Err(e),
-            Ok(o) => o,
-        };
+        passphrase: Option<&str>,
+    ) -> Result<(), SshError> {
+        let private_key = std::path::Path::new(pkey);
 
-        // clone tcp instance
-        let arc_tcp = Arc::new(Mutex::new(tcp));
-        let tcp_clone;
-        {
-            let locked_tcp = arc_tcp.lock().unwrap();
-            tcp_clone = locked_tcp.try_clone().unwrap();
+        if passphrase.is_none() && Ssh::is_key_encrypted(private_key)? {
+            return Err(SshError::PassphraseRequired(format!("{PASSPHRASE_REQUIRED}: {pkey}")));
         }
 
-        let mut session = Session::new().unwrap();
-        session.set_tcp_stream(tcp_clone);
+        let (tcp, session) = self._get_session(host, port)?;
 
-        if let Err(e) = session.handshake() {
-            return Err(format!("SSH handshake error: {}", e));
+        let methods = Ssh::auth_methods(&session, user);
+        let r = session
+            .userauth_pubkey_file(user, None, private_key, passphrase)
+            .map_err(|e| SshError::Auth(format!("Authentication error: {e}")));
+        self.userauth_continue(&session, user, None, &methods, r)?;
+
+        self._set_session(tcp, session, host, port, user)?;
+        self.private_key = pkey.to_string();
+        self.passphrase = passphrase.map(str::to_string);
+        self.auth = Some(AuthMethod::PublicKey {
+            key: pkey.to_string(),
+        });
+        Ok(())
+    }
+    // try the methods the server allows: agent, given keys, default keys, then
+    // password or keyboard-interactive, partial successes continue with the next one
+    pub async fn connect(
+        &mut self,
+        host: &str,
+        port: u16,
+        user: &str,
+        keys: &[String],
+        passphrase: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<AuthMethod, SshError> {
+        let (tcp, session) = self._get_session(host, port)?;
+
+        let method = self.authenticate(&session, user, keys, passphrase, password)?;
+        println!("authenticated with: {:?}", method);
+
+        self._set_session(tcp, session, host, port, user)?;
+        if let Some(password) = password {
+            self.password = password.to_string();
+        }
+        if let AuthMethod::PublicKey { key } = &method {
+            self.private_key = key.clone();
+            self.passphrase = passphrase.map(str::to_string);
         }
+        self.auth = Some(method.clone());
+        Ok(method)
+    }
+    fn authenticate(
+        &self,
+        session: &Session,
+        user: &str,
+        keys: &[String],
+        passphrase: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<AuthMethod, SshError> {
+        let mut methods = match session.auth_methods(user) {
+            Err(_) if session.authenticated() => String::new(),
+            Err(e) => return Err(SshError::Auth(format!("Cannot list auth methods: {e}"))),
+            Ok(o) => o.to_string(),
+        };
+        println!("auth methods: {methods}");
+        let mut errors = Vec::new();
+        let mut method = AuthMethod::None;
+        let allowed = |methods: &str, m: &str| methods.split(',').any(|x| x == m);
+
+        let mut attempt = |session: &Session, m: AuthMethod, r: Result<(), SshError>| {
+            if let Err(e) = r {
+                errors.push(e);
+            }
+            if session.authenticated() {
+                method = m;
+                return (true, String::new());
+            }
+            // after a partial success the list changes
+            (false, session.auth_methods(user).unwrap_or_default().to_string())
+        };
 
-        let private_key = std::path::Path::new(pkey);
+        let mut done = session.authenticated();
+        if !done && allowed(&methods, "publickey") {
+            let r = self.userauth_agent(session, user);
+            (done, methods) = attempt(session, AuthMethod::Agent, r);
 
-        if let Err(e) = session.userauth_pubkey_file(user, None, private_key, None) {
-            return Err(format!("Authentication error: {e}"));
+            let mut identities: Vec<PathBuf> = keys.iter().map(PathBuf::from).collect();
+            for key in Ssh::default_identities() {
+                if !identities.contains(&key) {
+                    identities.push(key);
+                }
+            }
+            for key in identities {
+                if done || !allowed(&methods, "publickey") {
+                    break;
+                }
+                let r = Ssh::userauth_key(session, user, &key, passphrase);
+                let m = AuthMethod::PublicKey {
+                    key: key.to_string_lossy().to_string(),
+                };
+                (done, methods) = attempt(session, m, r);
+            }
+        }
+        if let Some(password) = password {
+            if !done && allowed(&methods, "password") {
+                let r = session
+                    .userauth_password(user, password)
+                    .map_err(|e| SshError::Auth(format!("Password authentication error: {e}")));
+                (done, methods) = attempt(session, AuthMethod::Password, r);
+            }
+        }
+        if !done && allowed(&methods, "keyboard-interactive") {
+            let r = self.userauth_keyboard_interactive(session, user, password);
+            (done, _) = attempt(session, AuthMethod::KeyboardInteractive, r);
         }
 
-        assert!(session.authenticated());
+        if !done {
+            // the user can retry with the passphrase of a skipped key
+            if let Some(e) = errors
+                .iter()
+                .find(|e| matches!(e, SshError::PassphraseRequired(_)))
+            {
+                return Err(e.clone());
+            }
+            let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            return Err(SshError::Auth(format!("Authentication error: {}", errors.join(", "))));
+        }
+        Ok(method)
+    }
+    fn userauth_key(
+        session: &Session,
+        user: &str,
+        pkey: &Path,
+        passphrase: Option<&str>,
+    ) -> Result<(), SshError> {
+        if passphrase.is_none() && Ssh::is_key_encrypted(pkey)? {
+            let e = format!("{PASSPHRASE_REQUIRED}: {}", pkey.display());
+            return Err(SshError::PassphraseRequired(e));
+        }
+        match session.userauth_pubkey_file(user, None, pkey, passphrase) {
+            Err(e) => Err(SshError::Auth(format!("Key {} error: {e}", pkey.display()))),
+            Ok(_) => Ok(()),
+        }
+    }
+    // openssh keys name their cipher after the magic, pem keys have encryption headers
+    pub fn is_key_encrypted(pkey: &Path) -> Result<bool, SshError> {
+        let text = match std::fs::read_to_string(pkey) {
+            Err(e) => {
+                let e = format!("Cannot read private key {}: {e}", pkey.display());
+                return Err(SshError::Auth(e));
+            }
+            Ok(o) => o,
+        };
+        if text.contains("BEGIN ENCRYPTED PRIVATE KEY") || text.contains("Proc-Type: 4,ENCRYPTED") {
+            return Ok(true);
+        }
+        if !text.contains("BEGIN OPENSSH PRIVATE KEY") {
+            return Ok(false);
+        }
 
-        let sftp = match session.sftp() {
-            Err(e) => return Err(format!("Cannot create sftp channel {e}")),
+        let body: String = text
+            .lines()
+            .filter(|l| !l.starts_with("-----"))
+            .collect::<Vec<_>>()
+            .concat();
+        let data = match STANDARD.decode(body.trim()) {
+            Err(e) => {
+                let e = format!("Invalid private key {}: {e}", pkey.display());
+                return Err(SshError::Auth(e));
+            }
             Ok(o) => o,
         };
+        // "openssh-key-v1\0", then the cipher name as a u32 length and bytes
+        let magic = b"openssh-key-v1\0";
+        if data.len() < magic.len() + 4 || !data.starts_with(magic) {
+            return Err(SshError::Auth(format!("Invalid private key {}", pkey.display())));
+        }
+        let len_bytes: [u8; 4] = data[magic.len()..magic.len() + 4].try_into().unwrap();
+        let start = magic.len() + 4;
+        let end = start + u32::from_be_bytes(len_bytes) as usize;
+        match data.get(start..end) {
+            None => Err(SshError::Auth(format!("Invalid private key {}", pkey.display()))),
+            Some(cipher) => Ok(cipher != b"none"),
+        }
+    }
+    // try every identity of the ssh agent, agent_path, SSH_AUTH_SOCK or pageant
+    fn userauth_agent(&self, session: &Session, user: &str) -> Result<(), SshError> {
+        let mut agent =
+            LocalAgent::new(session, self.agent_path.as_deref()).map_err(SshError::Auth)?;
+        agent.connect().map_err(SshError::Auth)?;
+        let identities = agent.comments().map_err(SshError::Auth)?;
+        if identities.is_empty() {
+            return Err(SshError::Auth("No identities in ssh agent".to_string()));
+        }
 
-        session.set_blocking(false);
+        for (i, comment) in identities.iter().enumerate() {
+            match agent.userauth(user, i) {
+                Err(e) => println!("agent key rejected: {comment} {e}"),
+                Ok(_) => {
+                    println!("authenticated with agent key: {comment}");
+                    break;
+                }
+            }
+        }
+        drop(agent);
 
-        self.tcp = Some(arc_tcp);
-        self.session = Some(session);
-        self.sftp = Some(sftp);
-        self.host = host.to_string();
-        self.user = user.to_string();
-        self.private_key = pkey.to_string();
+        if !session.authenticated() {
+            let e = "Authentication error: no ssh agent identity accepted";
+            return Err(SshError::Auth(e.to_string()));
+        }
+        Ok(())
+    }
+    pub async fn connect_with_agent(
+        &mut self,
+        host: &str,
+        port: u16,
+        user: &str,
+    ) -> Result<(), SshError> {
+        let (tcp, session) = self._get_session(host, port)?;
+
+        let methods = Ssh::auth_methods(&session, user);
+        let r = self.userauth_agent(&session, user);
+        self.userauth_continue(&session, user, None, &methods, r)?;
+
+        self._set_session(tcp, session, host, port, user)?;
+        self.auth = Some(AuthMethod::Agent);
+        Ok(())
+    }
+    // answer server challenges with the prompt, otp codes for example,
+    // a password prompt is answered with the given password first
+    fn userauth_keyboard_interactive(
+        &self,
+        session: &Session,
+        user: &str,
+        password: Option<&str>,
+    ) -> Result<(), SshError> {
+        let mut error = String::from("no attempts");
+        for _ in 0..KBD_ATTEMPTS {
+            let mut prompter = auth::Prompter::new(self.challenge_prompt.as_deref(), password);
+            match session.userauth_keyboard_interactive(user, &mut prompter) {
+                Ok(_) => return Ok(()),
+                Err(e) => error = e.to_string(),
+            }
+            if prompter.cancelled {
+                return Err(SshError::Auth("Authentication cancelled".to_string()));
+            }
+        }
+        Err(SshError::Auth(format!("Authentication error: {error}")))
+    }
+    fn auth_methods(session: &Session, user: &str) -> String {
+        session.auth_methods(user).unwrap_or_default().to_string()
+    }
+    // a method can succeed partially, the server then lists what is still required,
+    // publickey followed by an otp through keyboard-interactive for example,
+    // before is the list from before the attempt, a plain failure leaves it unchanged
+    fn userauth_continue(
+        &self,
+        session: &Session,
+        user: &str,
+        password: Option<&str>,
+        before: &str,
+        result: Result<(), SshError>,
+    ) -> Result<(), SshError> {
+        if session.authenticated() {
+            return Ok(());
+        }
+        let methods = Ssh::auth_methods(session, user);
+        println!("remaining auth methods: {methods}");
+        if methods == before || !methods.split(',').any(|m| m == "keyboard-interactive") {
+            result?;
+            let e = "Authentication error: more authentication required";
+            return Err(SshError::Auth(e.to_string()));
+        }
+        self.userauth_keyboard_interactive(session, user, password)
+    }
+    pub async fn connect_with_keyboard_interactive(
+        &mut self,
+        host: &str,
+        port: u16,
+        user: &str,
+    ) -> Result<(), SshError> {
+        let (tcp, session) = self._get_session(host, port)?;
+
+        self.userauth_keyboard_interactive(&session, user, None)?;
+
+        self._set_session(tcp, session, host, port, user)?;
+        self.auth = Some(AuthMethod::KeyboardInteractive);
         Ok(())
     }
-    pub fn disconnect(&mut self) -> Result<(), String> {
+    // connect again after the connection dropped, authenticated like the first time,
+    // terminals are reopened with their ids and last sizes, forwards are not restored
+    pub fn reconnect(&mut self) -> Result<(), SshError> {
+        let method = match &self.auth {
+            None => return Err(SshError::Connect("Not connected".to_string())),
+            Some(o) => o.clone(),
+        };
+        let (host, port, user) = (self.host.clone(), self.port, self.user.clone());
+        self.release();
+
+        let (tcp, session) = self._get_session(&host, port)?;
+        let password = Some(self.password.as_str()).filter(|p| !p.is_empty());
+        let methods = Ssh::auth_methods(&session, &user);
+        let r = match &method {
+            AuthMethod::None => Ok(()),
+            AuthMethod::Agent => self.userauth_agent(&session, &user),
+            AuthMethod::PublicKey { key } => {
+                Ssh::userauth_key(&session, &user, Path::new(key), self.passphrase.as_deref())
+            }
+            AuthMethod::Password => session
+                .userauth_password(&user, password.unwrap_or_default())
+                .map_err(|e| SshError::Auth(format!("Authentication error: {e}"))),
+            AuthMethod::KeyboardInteractive => {
+                self.userauth_keyboard_interactive(&session, &user, password)
+            }
+        };
+        self.userauth_continue(&session, &user, password, &methods, r)?;
+        self._set_session(tcp, session, &host, port, &user)?;
+
+        let ptys: Vec<(u32, Arc<Mutex<RawChannel>>)> = self
+            .ptys
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, pty)| (*id, Arc::clone(pty)))
+            .collect();
+        for (id, pty) in ptys {
+            let channel = self.open_shell()?;
+            if let Some((cols, rows)) = self.sizes.get(&id) {
+                let _ = channel.request_pty_size(*cols, *rows);
+            }
+            // the writer threads keep their handle
+            *pty.lock().unwrap() = channel;
+        }
+        println!("reconnected to {host}:{port}");
+        Ok(())
+    }
+    // local listeners and remote listeners with their connections, x11 and agent relays
+    fn stop_relays(&mut self) {
+        for (_, forward) in self.forwards.drain() {
+            forward.stop();
+        }
+        if let Some(x11) = self.x11.take() {
+            x11.stop();
+        }
+        if let Some(agent) = self.agent.take() {
+            agent.stop();
+        }
+    }
+    // forget a dead connection
+    fn release(&mut self) {
+        self.stop_relays();
+        if let Some(session) = &self.session {
+            incoming::forget(session);
+        }
+        self.sftp = None;
+        self.session = None;
+        self.tcp = None;
+        self.jump_sessions.clear();
+    }
+    pub fn disconnect(&mut self) -> Result<(), SshError> {
         // if let Err(e) = self.session.as_ref().unwrap().disconnect(None, "", None) {
         //     return Err(e.to_string());
         // }
 
+        self.stop_relays();
+
         // Disconnect target session
         if let Some(session) = &self.session {
+            incoming::forget(session);
             if let Err(e) = session.disconnect(None, "", None) {
-                return Err(e.to_string());
+                return Err(SshError::Connect(e.to_string()));
             }
         }
         
-        // Disconnect jump server session
-        if let Some(jump_session) = &self.jump_session {
-            if let Err(e) = jump_session.disconnect(None, "", None) {
-                return Err(e.to_string());
+        // Disconnect jump server sessions from the last hop, they run non-blocking for the tunnels
+        for jump_session in self.jump_sessions.drain(..).rev() {
+            if let Err(e) = Ssh::retry(|| jump_session.disconnect(None, "", None)) {
+                return Err(SshError::Connect(e.to_string()));
             }
         }
         Ok(())
     }
-
-    // Add this method for jump server configuration
+    // hops in connection order, the first one is reached directly
+    pub fn set_jumps(&mut self, jumps: Vec<Jump>) {
+        self.jumps = jumps.into_iter().filter(|j| !j.host.is_empty()).collect();
+    }
+    // %h and %p are replaced with the host and port, an empty command connects directly
+    pub fn set_proxy_command(&mut self, cmd: &str) {
+        self.proxy_command = cmd.trim().to_string();
+    }
+    // request X11 forwarding on the terminals, opt-in like ssh -X
+    pub fn set_x11_forwarding(&mut self, enabled: bool) {
+        self.x11_forwarding = enabled;
+    }
+    // relay agent requests of the terminals to the local agent, like ssh -A
+    pub fn set_agent_forwarding(&mut self, enabled: bool) {
+        self.agent_forwarding = enabled;
+    }
+    // the agent for authentication and forwarding, like IdentityAgent
+    pub fn set_agent_path(&mut self, path: &str) {
+        self.agent_path = Some(path.to_string()).filter(|p| !p.is_empty());
+    }
+    // like ServerAliveInterval and ServerAliveCountMax, a count of 0 uses 3
+    pub fn set_keepalive(&mut self, interval: u32, count_max: u32) {
+        self.keepalive_interval = interval;
+        self.keepalive_count_max = if count_max == 0 { 3 } else { count_max };
+    }
+    pub fn keepalive(&self) -> (u32, u32) {
+        (self.keepalive_interval, self.keepalive_count_max)
+    }
+    pub fn keepalive_send(&self) -> Result<(), SshError> {
+        let session = self.connected()?;
+        match Ssh::retry(|| session.keepalive_send()) {
+            Err(e) => Err(SshError::Connect(format!("Cannot send keepalive: {e}"))),
+            Ok(_) => Ok(()),
+        }
+    }
+    pub fn set_env(&mut self, env: BTreeMap<String, String>) {
+        self.env = env;
+    }
+    // empty values leave the shell in the home directory and idle
+    pub fn set_startup(&mut self, working_dir: &str, command: &str) {
+        self.working_dir = working_dir.trim().to_string();
+        self.startup_command = command.trim().to_string();
+    }
     pub fn set_jump_server(&mut self, jump_host: &str, jump_user: &str, jump_password: &str) {
-        self.jump_host = jump_host.to_string();
-        self.jump_user = jump_user.to_string();
-        self.jump_password = jump_password.to_string();
+        self.set_jumps(vec![Jump {
+            host: jump_host.to_string(),
+            user: jump_user.to_string(),
+            password: Some(jump_password.to_string()),
+            ..Default::default()
+        }]);
     }
-
     pub fn set_jump_server_with_key(&mut self, jump_host: &str, jump_user: &str, jump_private_key: &str) {
-        self.jump_host = jump_host.to_string();
-        self.jump_user = jump_user.to_string();
-        self.jump_private_key = jump_private_key.to_string();
+        self.set_jumps(vec![Jump {
+            host: jump_host.to_string(),
+            user: jump_user.to_string(),
+            private_key: Some(jump_private_key.to_string()),
+            ..Default::default()
+        }]);
     }
 
-    // New method for connecting through jump server
-    async fn connect_with_password_via_jump(
-        &mut self,
-        target_host: &str,
-        target_port: u16,
-        target_user: &str,
-        target_password: &str,
-    ) -> Result<(), String> {
-        // 1. Connect to jump server
-        let jump_tcp = match self._get_tcp(&self.jump_host, 22) {
-            Err(e) => return Err(format!("Jump server connection failed: {e}")),
-            Ok(o) => o,
-        };
-
-        let arc_jump_tcp = Arc::new(Mutex::new(jump_tcp));
-        let jump_tcp_clone = {
-            let locked_tcp = arc_jump_tcp.lock().unwrap();
-            locked_tcp.try_clone().unwrap()
-        };
-
-        let mut jump_session = Session::new().unwrap();
-        jump_session.set_tcp_stream(jump_tcp_clone);
-        
-        if let Err(e) = jump_session.handshake() {
-            return Err(format!("Jump server SSH handshake error: {}", e));
-        }
-
-        if let Err(e) = jump_session.userauth_password(&self.jump_user, &self.jump_password) {
-            return Err(format!("Jump server authentication error: {e}"));
+    // the session, or an error before connect and after a dropped connection
+    pub fn connected(&self) -> Result<&Session, SshError> {
+        match &self.session {
+            None => Err(SshError::Connect("Not connected".to_string())),
+            Some(o) => Ok(o),
         }
-
-        // 2. Create tunnel through jump server
-        let tunnel_channel = match jump_session.channel_direct_tcpip(target_host, target_port, None) {
-            Err(e) => return Err(format!("Failed to create tunnel: {e}")),
-            Ok(o) => o,
-        };
-
-        // 3. Create target session using tunnel
-        let mut target_session = Session::new().unwrap();
-        target_session.set_tcp_stream(tunnel_channel);
-
-        if let Err(e) = target_session.handshake() {
-            return Err(format!("Target SSH handshake error: {}", e));
-        }
-
-        if let Err(e) = target_session.userauth_password(target_user, target_password) {
-            return Err(format!("Target authentication error: {e}"));
+    }
+    fn sftp(&self) -> Result<&Sftp, SshError> {
+        match &self.sftp {
+            None => Err(SshError::Connect("Not connected".to_string())),
+            Some(o) => Ok(o),
         }
-
-        let sftp = match target_session.sftp() {
-            Err(e) => return Err(format!("Cannot create sftp channel {e}")),
-            Ok(o) => o,
-        };
-
-        target_session.set_blocking(false);
-
-        // Store all connections
-        self.jump_tcp = Some(arc_jump_tcp);
-        self.jump_session = Some(jump_session);
-        self.tunnel_channel = Some(tunnel_channel);
-        self.session = Some(target_session);
-        self.sftp = Some(sftp);
-        self.host = target_host.to_string();
-        self.user = target_user.to_string();
-        self.password = target_password.to_string();
-
-        Ok(())
     }
-
-
-    pub fn run(&mut self, cmd: &str) -> Result<String, String> {
+    pub fn run(&mut self, cmd: &str) -> Result<String, SshError> {
         println!("running CMD: {}", cmd);
+        let session = self.connected()?;
         let mut channel = loop {
-            match self.session.as_ref().unwrap().channel_session() {
+            match session.channel_session() {
                 Err(e) => {
                     if e.code() != ssh2::ErrorCode::Session(-37) {
-                        return Err(format!("Error: {}", e));
+                        return Err(SshError::Channel(format!("Error: {}", e)));
                     } else {
                         println!("bloking..., trying again.");
                     }
@@ -447,7 +967,7 @@ impl Ssh {
                         println!("bloking..., trying again.");
                         thread::sleep(time::Duration::from_millis(WAIT_MS));
                     } else {
-                        return Err(format!("Error channel exec: {}", e));
+                        return Err(SshError::Channel(format!("Error channel exec: {}", e)));
                     }
                 }
                 Ok(_) => break,
@@ -463,23 +983,26 @@ impl Ssh {
                         thread::sleep(time::Duration::from_millis(WAIT_MS));
                         continue;
                     } else {
-                        return Err(format!("Error channel read stderr: {}", e));
+                        let e = format!("Error channel read stderr: {}", e);
+                        return Err(SshError::Channel(e));
                     }
                 }
                 Ok(_) => break,
             }
         }
         if !s.trim().is_empty() {
-            return Err(format!("stderr: {}", s));
+            return Err(SshError::Channel(format!("stderr: {}", s)));
         };
 
-        channel.read_to_string(&mut s).unwrap();
+        if let Err(e) = channel.read_to_string(&mut s) {
+            return Err(SshError::Channel(format!("Error channel read: {e}")));
+        }
 
         loop {
             match channel.wait_close() {
                 Err(e) => {
                     if e.code() != ssh2::ErrorCode::Session(-37) {
-                        return Err(format!("Error: {}", e));
+                        return Err(SshError::Channel(format!("Error: {}", e)));
                     } else {
                         println!("bloking..., trying again.");
                     }
@@ -492,248 +1015,388 @@ impl Ssh {
         println!("stdout: {output}");
         Ok(output)
     }
-    pub fn scp_download(
-        &mut self,
-        remotepath: &str,
-        localpath: &str,
-        _window: tauri::Window,
-    ) -> Result<String, String> {
-        println!("downloading: {remotepath}");
-        let (mut channel, stat) = match self
-            .session
-            .as_ref()
-            .unwrap()
-            .scp_recv(Path::new(remotepath))
-        {
-            Err(e) => return Err(format!("Cannot open scp channel: {}", e)),
-            Ok(o) => o,
-        };
-        let size = stat.size();
-        println!("remote file size: {}", size);
-        let f = File::create(localpath).expect("Unable to create file");
-        let mut f = BufWriter::new(f);
-        let mut buffer = [0; 16000];
-        let mut count = 0;
-        let mut prev_percent = 0;
-        loop {
-            match channel.read(&mut buffer[..]) {
-                Err(e) => {
-                    println!("error: {:?}", e);
-                    return Err(e.to_string());
-                }
-                Ok(n) => {
-                    // println!("{n} bytes read");
-                    if n == 0 {
-                        break;
-                    } else {
-                        f.write(&buffer[..n]).unwrap();
-                        count += n;
-                    }
-                    // report progress
-                    let percent = ((count as f64 / size as f64) * 100.) as i32;
-                    if prev_percent != percent {
-                        let _p = percent as f32 / 100.;
-                        //window.emit("PROGRESS", Payload { percent: p }).unwrap();
-                        prev_percent = percent;
-                    }
-                }
-            }
-        }
-        println!("written: {count}");
-        //window.emit("PROGRESS", Payload { percent: 0. }).unwrap();
-        Ok("done".to_string())
-    }
-    pub fn scp_upload(
-        &mut self,
-        localpath: &str,
-        remotepath: &str,
-        _window: tauri::Window,
-    ) -> Result<String, String> {
-        println!("uploading: {localpath} to {remotepath}");
-        let size = std::fs::metadata(localpath).unwrap().len();
-        let mut channel =
-            match self
-                .session
-                .as_ref()
-                .unwrap()
-                .scp_send(Path::new(remotepath), 0o644, size, None)
-            {
-                Err(e) => return Err(format!("Cannot open scp channel: {}", e)),
-                Ok(o) => o,
-            };
-        println!("file size: {}", size);
-        let f = File::open(localpath).expect("Unable to open file");
-        let mut f = BufReader::new(f);
-        let mut buffer = [0; 16000];
-        let mut count = 0;
-        let mut prev_percent = 0;
-        loop {
-            let n = f.read(&mut buffer).unwrap();
-            match channel.write(&buffer[..n]) {
-                Err(e) => {
-                    println!("error: {:?}", e);
-                    return Err(e.to_string());
-                }
-                Ok(n) => {
-                    count += n;
-                    if n < 16000 {
-                        break;
-                    }
-                }
-            }
-            // report progress
-            let percent = ((count as f64 / size as f64) * 100.) as i32;
-            if prev_percent != percent {
-                let _p = percent as f32 / 100.;
-                //window.emit("PROGRESS", Payload { percent: p }).unwrap();
-                prev_percent = percent;
-            }
-        }
-
-        println!("written: {count}");
-        //window.emit("PROGRESS", Payload { percent: 0. }).unwrap();
-        Ok("done".to_string())
-    }
-    pub fn sftp_stat(&mut self, filename: &str) -> Result<FileStat, String> {
-        match self.sftp.as_ref().unwrap().lstat(Path::new(filename)) {
-            Err(e) => Err(format!("Cannot stat {filename}: {e}")),
+    pub fn sftp_stat(&mut self, filename: &str) -> Result<FileStat, SshError> {
+        let sftp = self.sftp()?;
+        match Ssh::retry(|| sftp.lstat(Path::new(filename))) {
+            Err(e) => Err(SshError::Sftp(format!("Cannot stat {filename}: {e}"))),
             Ok(o) => Ok(o),
         }
     }
-    pub fn sftp_mkdir(&mut self, dirname: &str) -> Result<(), String> {
-        match self.sftp.as_ref().unwrap().mkdir(Path::new(dirname), 0o755) {
-            Err(e) => Err(format!("Cannot make dir {dirname}: {e}")),
+    pub fn sftp_mkdir(&mut self, dirname: &str) -> Result<(), SshError> {
+        let sftp = self.sftp()?;
+        match Ssh::retry(|| sftp.mkdir(Path::new(dirname), 0o755)) {
+            Err(e) => Err(SshError::Sftp(format!("Cannot make dir {dirname}: {e}"))),
             Ok(_) => Ok(()),
         }
     }
-    pub fn sftp_rmdir(&mut self, dirname: &str) -> Result<(), String> {
-        match self.sftp.as_ref().unwrap().rmdir(Path::new(dirname)) {
-            Err(e) => Err(format!("Cannot delete dir {dirname}: {e}")),
+    pub fn sftp_rmdir(&mut self, dirname: &str) -> Result<(), SshError> {
+        let sftp = self.sftp()?;
+        match Ssh::retry(|| sftp.rmdir(Path::new(dirname))) {
+            Err(e) => Err(SshError::Sftp(format!("Cannot delete dir {dirname}: {e}"))),
             Ok(_) => Ok(()),
         }
     }
-    pub fn sftp_create(&mut self, filename: &str) -> Result<ssh2::File, String> {
-        let f = match self.sftp.as_ref().unwrap().create(Path::new(filename)) {
-            Err(e) => return Err(format!("Cannot create file {filename}: {e}")),
+    pub fn sftp_create(&mut self, filename: &str) -> Result<ssh2::File, SshError> {
+        let sftp = self.sftp()?;
+        let f = match Ssh::retry(|| sftp.create(Path::new(filename))) {
+            Err(e) => return Err(SshError::Sftp(format!("Cannot create file {filename}: {e}"))),
             Ok(o) => o,
         };
         Ok(f)
     }
-    pub fn sftp_open(&mut self, filename: &str) -> Result<ssh2::File, String> {
-        let f = match self.sftp.as_ref().unwrap().open(Path::new(filename)) {
-            Err(e) => return Err(format!("Cannot open file {filename}: {e}")),
+    pub fn sftp_open(&mut self, filename: &str) -> Result<ssh2::File, SshError> {
+        let sftp = self.sftp()?;
+        let f = match Ssh::retry(|| sftp.open(Path::new(filename))) {
+            Err(e) => return Err(SshError::Sftp(format!("Cannot open file {filename}: {e}"))),
             Ok(o) => o,
         };
         Ok(f)
     }
-    pub fn sftp_rename(&mut self, src: &str, dst: &str) -> Result<(), String> {
+    pub fn sftp_rename(&mut self, src: &str, dst: &str) -> Result<(), SshError> {
         let s = Path::new(src);
         let d = Path::new(dst);
-        let sftp = self.sftp.as_ref().unwrap();
-        if let Err(e) = sftp.rename(&s, &d, None) {
-            return Err(format!("Cannot rename {src}: {e}"));
+        let sftp = self.sftp()?;
+        if let Err(e) = Ssh::retry(|| sftp.rename(s, d, None)) {
+            return Err(SshError::Sftp(format!("Cannot rename {src}: {e}")));
         }
         Ok(())
     }
-    pub fn sftp_delete(&mut self, filename: &str) -> Result<(), String> {
+    pub fn sftp_delete(&mut self, filename: &str) -> Result<(), SshError> {
         println!("deleting {filename}");
 
         let path = Path::new(filename);
-        let sftp = self.sftp.as_ref().unwrap();
-        let stat = match sftp.lstat(path) {
-            Err(e) => return Err(format!("{filename}: {e}")),
+        let sftp = self.sftp()?;
+        let stat = match Ssh::retry(|| sftp.lstat(path)) {
+            Err(e) => return Err(SshError::Sftp(format!("{filename}: {e}"))),
             Ok(o) => o,
         };
         if stat.file_type().is_symlink() || stat.file_type().is_file() {
             //println!("{filename} is file or link");
-            match sftp.unlink(path) {
-                Err(e) => Err(format!("Cannot delete {filename}: {e}")),
+            match Ssh::retry(|| sftp.unlink(path)) {
+                Err(e) => Err(SshError::Sftp(format!("Cannot delete {filename}: {e}"))),
                 Ok(_) => Ok(()),
             }
         } else {
             //println!("file is folder: {filename}");
-            let files: Vec<(PathBuf, FileStat)> = match sftp.readdir(path) {
-                Err(e) => return Err(format!("Cannot read directory {filename}: {e}")),
+            let files: Vec<(PathBuf, FileStat)> = match Ssh::retry(|| sftp.readdir(path)) {
+                Err(e) => return Err(SshError::Sftp(format!("Cannot read directory {filename}: {e}"))),
                 Ok(o) => o,
             };
             //println!("files in: {filename}: {}: {:?}", files.len(), files);
             if files.len() > 0 {
                 for (f, _) in files {
-                    if let Err(e) = self.sftp_delete(f.clone().to_str().unwrap()) {
-                        return Err(format!("Cannot delete directory {filename}: {e}"));
+                    if let Err(e) = self.sftp_delete(&f.to_string_lossy()) {
+                        return Err(SshError::Sftp(format!("Cannot delete directory {filename}: {e}")));
                     }
                 }
             }
             println!("rmdir folder: {filename}");
-            match self.sftp.as_ref().unwrap().rmdir(path) {
-                Err(e) => return Err(format!("Cannot delete directory {filename}: {e}")),
+            let sftp = self.sftp()?;
+            match Ssh::retry(|| sftp.rmdir(path)) {
+                Err(e) => return Err(SshError::Sftp(format!("Cannot delete directory {filename}: {e}"))),
                 Ok(_) => Ok(()),
             }
         }
     }
-    pub fn sftp_readdir(&mut self, dirname: &str) -> Result<Vec<(PathBuf, FileStat)>, String> {
+    pub fn sftp_readdir(&mut self, dirname: &str) -> Result<Vec<(PathBuf, FileStat)>, SshError> {
         let path = Path::new(dirname);
-        let files: Vec<(PathBuf, FileStat)> = match self.sftp.as_ref().unwrap().readdir(path) {
-            Err(e) => return Err(format!("Cannot read directory {dirname}: {e}")),
+        let sftp = self.sftp()?;
+        let files: Vec<(PathBuf, FileStat)> = match Ssh::retry(|| sftp.readdir(path)) {
+            Err(e) => return Err(SshError::Sftp(format!("Cannot read directory {dirname}: {e}"))),
             Ok(o) => o,
         };
         Ok(files)
     }
-    pub fn sftp_readlink(&mut self, filename: &str) -> Result<String, String> {
+    pub fn sftp_readlink(&mut self, filename: &str) -> Result<String, SshError> {
         let path = Path::new(filename);
-        let destination = match self.sftp.as_ref().unwrap().readlink(path) {
-            Err(e) => return Err(format!("Cannot read path {filename}: {e}")),
+        let sftp = self.sftp()?;
+        let destination = match Ssh::retry(|| sftp.readlink(path)) {
+            Err(e) => return Err(SshError::Sftp(format!("Cannot read path {filename}: {e}"))),
             Ok(o) => o,
         };
         Ok(String::from(destination.to_string_lossy()))
     }
-    pub fn sftp_realpath(&mut self, filename: &str) -> Result<(String, FileStat), String> {
+    pub fn sftp_realpath(&mut self, filename: &str) -> Result<(String, FileStat), SshError> {
         let path = Path::new(filename);
-        let sftp = self.sftp.as_ref().unwrap();
-        let destination = match self.sftp.as_ref().unwrap().realpath(path) {
-            Err(e) => return Err(format!("Cannot read real path {filename}: {e}")),
+        let sftp = self.sftp()?;
+        let destination = match Ssh::retry(|| sftp.realpath(path)) {
+            Err(e) => return Err(SshError::Sftp(format!("Cannot read real path {filename}: {e}"))),
             Ok(o) => o,
         };
-        let stat = match sftp.stat(&destination) {
-            Err(e) => return Err(format!("Cannot stat {filename}: {e}")),
+        let stat = match Ssh::retry(|| sftp.stat(&destination)) {
+            Err(e) => return Err(SshError::Sftp(format!("Cannot stat {filename}: {e}"))),
             Ok(o) => o,
         };
         Ok((String::from(destination.to_string_lossy()), stat))
     }
-    pub fn sftp_save(&mut self, filename: &str, data: &str) -> Result<(), String> {
-        let mut f = match self.sftp.as_ref().unwrap().create(Path::new(filename)) {
-            Err(e) => return Err(format!("Cannot create file {filename}: {e}")),
+    pub fn sftp_save(&mut self, filename: &str, data: &str) -> Result<(), SshError> {
+        let sftp = self.sftp()?;
+        let mut f = match Ssh::retry(|| sftp.create(Path::new(filename))) {
+            Err(e) => return Err(SshError::Sftp(format!("Cannot create file {filename}: {e}"))),
             Ok(o) => o,
         };
-        f.write_all(data.as_bytes()).expect("Cannot write data");
+        let mut data = data.as_bytes();
+        while !data.is_empty() {
+            match f.write(data) {
+                Ok(n) => data = &data[n..],
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    thread::sleep(time::Duration::from_millis(WAIT_MS));
+                }
+                Err(e) => return Err(SshError::Sftp(format!("Cannot write file {filename}: {e}"))),
+            }
+        }
         Ok(())
     }
-    pub fn channel_shell(&mut self) -> Result<(), String> {
-        let session = self.session.as_ref().unwrap();
-        session.set_blocking(true);
-        let mut pty = session.channel_session().unwrap();
-
-        // setenv not working
-        //p ty.setenv("FOO","VAR").unwrap();
-
-        pty.request_pty("xterm-256color", None, None).unwrap();
-        pty.shell().unwrap();
-
-        self.pty = Some(Arc::new(Mutex::new(pty)));
-        session.set_blocking(false);
-        Ok(())
+    // lstat for the file browser, symlinks come with their target
+    pub fn sftp_entry(&mut self, filename: &str) -> Result<FileEntry, SshError> {
+        let stat = self.sftp_stat(filename)?;
+        let mut entry = FileEntry::new(Path::new(filename), &stat);
+        if entry.kind == FileKind::Symlink {
+            entry.target = self.sftp_readlink(filename).ok();
+        }
+        Ok(entry)
     }
-    pub fn channel_shell_size(&mut self, cols: u32, rows: u32) -> Result<(), String> {
-        match self
-            .pty
-            .as_ref()
-            .unwrap()
+    pub fn sftp_list(&mut self, dirname: &str) -> Result<Vec<FileEntry>, SshError> {
+        let mut entries = Vec::new();
+        for (path, stat) in self.sftp_readdir(dirname)? {
+            let mut entry = FileEntry::new(&path, &stat);
+            if entry.kind == FileKind::Symlink {
+                entry.target = self.sftp_readlink(&entry.path).ok();
+            }
+            entries.push(entry);
+        }
+        Ok(files::sort(entries))
+    }
+    pub fn retry<T>(mut f: impl FnMut() -> Result<T, ssh2::Error>) -> Result<T, ssh2::Error> {
+        loop {
+            match f() {
+                Err(e) if e.code() == ssh2::ErrorCode::Session(-37) => {
+                    thread::sleep(time::Duration::from_millis(WAIT_MS));
+                }
+                r => return r,
+            }
+        }
+    }
+    // the x11 forwarding is started with the first terminal, false when it cannot be
+    fn x11_relay(&mut self) -> bool {
+        if !self.x11_forwarding {
+            return false;
+        }
+        if self.x11.is_none() {
+            let session = match self.session.as_ref() {
+                None => return false,
+                Some(o) => o,
+            };
+            match x11::start(session) {
+                Err(e) => {
+                    println!("{e}");
+                    return false;
+                }
+                Ok(o) => self.x11 = Some(o),
+            }
+        }
+        true
+    }
+    // the relay is started with the first terminal, false when it cannot be
+    fn agent_relay(&mut self) -> bool {
+        if !self.agent_forwarding {
+            return false;
+        }
+        if self.agent.is_none() {
+            let session = match self.session.as_ref() {
+                None => return false,
+                Some(o) => o,
+            };
+            match agent::start(session, self.agent_path.clone()) {
+                Err(e) => {
+                    println!("{e}");
+                    return false;
+                }
+                Ok(o) => self.agent = Some(o),
+            }
+        }
+        true
+    }
+    pub fn channel_shell(&mut self) -> Result<u32, SshError> {
+        let pty = self.open_shell()?;
+        self.next_pty += 1;
+        let id = self.next_pty;
+        self.ptys
             .lock()
             .unwrap()
-            .request_pty_size(cols, rows, None, None)
-        {
+            .insert(id, Arc::new(Mutex::new(pty)));
+        Ok(id)
+    }
+    // a raw channel, the x11 request is not in the ssh2 crate
+    fn open_shell(&mut self) -> Result<RawChannel, SshError> {
+        let forward_x11 = self.x11_relay();
+        let forward_agent = self.agent_relay();
+        // the session stays non-blocking, other terminals may be reading
+        let session = self.connected()?;
+        let pty = RawChannel::open_session(session).map_err(SshError::Channel)?;
+
+        pty.request_pty("xterm-256color").map_err(SshError::Channel)?;
+        // the server sets DISPLAY for the shell
+        if let (true, Some(x11)) = (forward_x11, &self.x11) {
+            if let Err(e) = x11.request(&pty) {
+                println!("{e}");
+            }
+        }
+        if forward_agent {
+            if let Err(e) = pty.request_auth_agent() {
+                println!("{e}");
+            }
+        }
+        // servers usually refuse setenv, AcceptEnv is limited, then the shell exports it
+        let mut lines = Vec::new();
+        for (name, value) in &self.env {
+            if !is_env_name(name) {
+                println!("invalid environment variable name: {name}");
+                continue;
+            }
+            if pty.setenv(name, value).is_err() {
+                lines.push(format!("export {name}={}", command::quote(value)));
+            }
+        }
+        if !self.working_dir.is_empty() {
+            lines.push(format!("cd {}", command::quote(&self.working_dir)));
+        }
+        if !self.startup_command.is_empty() {
+            lines.push(self.startup_command.clone());
+        }
+        pty.shell().map_err(SshError::Channel)?;
+        if !lines.is_empty() {
+            let line = format!("{}\n", lines.join("; "));
+            if let Err(e) = pty.write_all(line.as_bytes()) {
+                println!("cannot set up terminal: {e}");
+            }
+        }
+        Ok(pty)
+    }
+    pub fn channel_pty(&self, id: u32) -> Result<Arc<Mutex<RawChannel>>, SshError> {
+        match self.ptys.lock().unwrap().get(&id) {
+            None => Err(SshError::Channel(format!("Terminal {id} not found"))),
+            Some(pty) => Ok(Arc::clone(pty)),
+        }
+    }
+    pub fn channel_shell_size(&mut self, id: u32, cols: u32, rows: u32) -> Result<(), SshError> {
+        let pty = self.channel_pty(id)?;
+        self.sizes.insert(id, (cols, rows));
+        let pty = pty.lock().unwrap();
+        match pty.request_pty_size(cols, rows) {
             Ok(_) => Ok(()),
-            Err(e) => Err(format!("Error resizing terminal: {:?}", e.message())),
+            Err(e) => Err(SshError::Channel(format!("Error resizing terminal: {e}"))),
+        }
+    }
+    pub fn channel_close(&mut self, id: u32) -> Result<(), SshError> {
+        let pty = match self.ptys.lock().unwrap().remove(&id) {
+            None => return Err(SshError::Channel(format!("Terminal {id} not found"))),
+            Some(o) => o,
+        };
+        self.sizes.remove(&id);
+        let pty = pty.lock().unwrap();
+        match pty.close() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(SshError::Channel(format!("Error closing terminal: {e}"))),
+        }
+    }
+    // the shell of a terminal ended, forget it and tell how
+    pub fn channel_exit(&mut self, id: u32) -> Result<TerminalExit, SshError> {
+        let pty = self.channel_take(id)?;
+        let pty = pty.lock().unwrap();
+        Ok(Ssh::terminal_exit(&pty))
+    }
+    // forget a terminal, terminal_exit can then wait for it without the ssh lock
+    pub fn channel_take(&mut self, id: u32) -> Result<Arc<Mutex<RawChannel>>, SshError> {
+        let pty = match self.ptys.lock().unwrap().remove(&id) {
+            None => return Err(SshError::Channel(format!("Terminal {id} not found"))),
+            Some(o) => o,
+        };
+        self.sizes.remove(&id);
+        Ok(pty)
+    }
+    pub fn terminal_exit(pty: &RawChannel) -> TerminalExit {
+        // the exit status and signal may come after the eof, they are there once closed
+        let timeout = time::Duration::from_millis(CLOSE_TIMEOUT_MS);
+        if let Err(e) = pty.close().and_then(|_| pty.wait_close(timeout)) {
+            println!("Error closing terminal: {e}");
+        }
+        let (signal, message) = pty.exit_signal();
+        // without a status libssh2 reports 0, a killed shell has none
+        let status = match signal {
+            Some(_) => None,
+            None => Some(pty.exit_status()),
+        };
+        TerminalExit {
+            status,
+            signal,
+            message: message.filter(|m| !m.is_empty()),
+        }
+    }
+    // local port forward, returns its id for forward_remove
+    pub fn forward_local(
+        &mut self,
+        bind_host: &str,
+        bind_port: u16,
+        host: &str,
+        port: u16,
+    ) -> Result<u32, SshError> {
+        let (session, tcp) = match (&self.session, &self.tcp) {
+            (Some(session), Some(tcp)) => (session, tcp.lock().unwrap()),
+            _ => return Err(SshError::Connect("Not connected".to_string())),
+        };
+        let forward = forward::local(session, &tcp, bind_host, bind_port, host, port)
+            .map_err(SshError::Channel)?;
+        drop(tcp);
+        self.next_forward += 1;
+        self.forwards.insert(self.next_forward, forward);
+        Ok(self.next_forward)
+    }
+    // remote port forward, the same ids as local forwards
+    pub fn forward_remote(
+        &mut self,
+        bind_host: &str,
+        bind_port: u16,
+        host: &str,
+        port: u16,
+    ) -> Result<u32, SshError> {
+        let (session, tcp) = match (&self.session, &self.tcp) {
+            (Some(session), Some(tcp)) => (session, tcp.lock().unwrap()),
+            _ => return Err(SshError::Connect("Not connected".to_string())),
+        };
+        let forward = forward::remote(session, &tcp, bind_host, bind_port, host, port)
+            .map_err(SshError::Channel)?;
+        drop(tcp);
+        self.next_forward += 1;
+        self.forwards.insert(self.next_forward, forward);
+        Ok(self.next_forward)
+    }
+    // socks5 proxy, listed and removed like the other forwards
+    pub fn forward_dynamic(&mut self, bind_host: &str, bind_port: u16) -> Result<u32, SshError> {
+        let (session, tcp) = match (&self.session, &self.tcp) {
+            (Some(session), Some(tcp)) => (session, tcp.lock().unwrap()),
+            _ => return Err(SshError::Connect("Not connected".to_string())),
+        };
+        let forward =
+            forward::dynamic(session, &tcp, bind_host, bind_port).map_err(SshError::Channel)?;
+        drop(tcp);
+        self.next_forward += 1;
+        self.forwards.insert(self.next_forward, forward);
+        Ok(self.next_forward)
+    }
+    pub fn forward_list(&self) -> Vec<ForwardInfo> {
+        let mut forwards: Vec<ForwardInfo> =
+            self.forwards.iter().map(|(id, f)| f.info(*id)).collect();
+        forwards.sort_by_key(|f| f.id);
+        forwards
+    }
+    pub fn forward_remove(&mut self, id: u32) -> Result<(), SshError> {
+        match self.forwards.remove(&id) {
+            None => Err(SshError::Channel(format!("Forward {id} not found"))),
+            Some(forward) => {
+                forward.stop();
+                Ok(())
+            }
         }
     }
 }
@@ -742,6 +1405,9 @@ impl Ssh {
 mod tests {
 
     use super::*;
+    use crate::progress::{recorded, Progress};
+    use crate::transfer::{self, Control, ResumeOptions};
+    use crate::tree::{self, SymlinkPolicy};
     use std::env;
     const PORT: u16 = 22;
 
@@ -778,41 +1444,136 @@ mod tests {
     }
 
 
+    // the test hosts are trusted without a prompt
+    fn trusting_ssh() -> Ssh {
+        let mut ssh = Ssh::new();
+        ssh.set_host_key_prompt(|_| true);
+        ssh
+    }
+
     #[tokio::test]
     async fn connect_with_password() {
-        let mut ssh = Ssh::new();
+        let mut ssh = trusting_ssh();
         let (host, user, pass, _) = get_params();
         let r = ssh.connect_with_password(&host, PORT, &user, &pass).await;
         assert!(r.is_ok());
     }
     #[tokio::test]
     async fn connect_with_password_wrong() {
-        let mut ssh = Ssh::new();
+        let mut ssh = trusting_ssh();
         let (host, user, _, _) = get_params();
         let r = ssh.connect_with_password(&host, PORT, &user, "wrong").await;
         assert!(r.is_err());
     }
     #[tokio::test]
     async fn connect_with_key() {
-        let mut ssh = Ssh::new();
+        let mut ssh = trusting_ssh();
         let (host, user, _, _) = get_params();
         let pkey = Ssh::private_key_path();
         let pkey = pkey.to_str().unwrap();
-        let r = ssh.connect_with_key(&host, PORT, &user, &pkey).await;
+        let r = ssh.connect_with_key(&host, PORT, &user, &pkey, None).await;
         assert!(r.is_ok());
     }
     #[tokio::test]
     async fn connect_with_key_wrong() {
-        let mut ssh = Ssh::new();
+        let mut ssh = trusting_ssh();
         let (host, user, _, _) = get_params();
         let r = ssh
-            .connect_with_key(&host, PORT, &user, "/invalid/key")
+            .connect_with_key(&host, PORT, &user, "/invalid/key", None)
             .await;
         assert!(r.is_err());
     }
+    // a local ssh-agent, returns its socket and pid
+    fn spawn_agent() -> (String, String) {
+        let (o, e, _) = command::run("ssh-agent -s");
+        assert_eq!(e, "");
+        let var = |name: &str| {
+            let start = o.find(&format!("{name}=")).unwrap() + name.len() + 1;
+            let end = start + o[start..].find(';').unwrap();
+            o[start..end].to_string()
+        };
+        (var("SSH_AUTH_SOCK"), var("SSH_AGENT_PID"))
+    }
+    #[tokio::test]
+    async fn connect_with_agent() {
+        let (host, user, _, _) = get_params();
+        let (sock, pid) = spawn_agent();
+
+        // no identities yet
+        let mut ssh = trusting_ssh();
+        ssh.set_agent_path(&sock);
+        let r = ssh.connect_with_agent(&host, PORT, &user).await;
+        assert!(r.is_err());
+
+        let pkey = Ssh::private_key_path();
+        let cmd = format!("SSH_AUTH_SOCK={sock} ssh-add {}", pkey.display());
+        let (_, _, status) = command::run(&cmd);
+        assert_eq!(status, 0);
+        let mut ssh = trusting_ssh();
+        ssh.set_agent_path(&sock);
+        let r = ssh.connect_with_agent(&host, PORT, &user).await;
+        assert!(r.is_ok());
+        assert_eq!(user, ssh.run("whoami").unwrap());
+
+        command::run(&format!("SSH_AGENT_PID={pid} ssh-agent -k"));
+    }
+    fn generate_test_key(name: &str, args: &str) -> PathBuf {
+        let path = env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(path.with_extension("pub"));
+        let cmd = format!("ssh-keygen -q -t ed25519 {args} -f {}", path.display());
+        let (_, e, _) = command::run(&cmd);
+        assert_eq!(e, "");
+        path
+    }
+    #[test]
+    fn key_encrypted() {
+        let plain = generate_test_key("xtauri_key_plain", "-N \"\"");
+        assert!(!Ssh::is_key_encrypted(&plain).unwrap());
+        let encrypted = generate_test_key("xtauri_key_encrypted", "-N secret");
+        assert!(Ssh::is_key_encrypted(&encrypted).unwrap());
+    }
+    #[test]
+    fn key_encrypted_pem() {
+        let plain = generate_test_key("xtauri_key_plain_pem", "-m PEM -N \"\"");
+        assert!(!Ssh::is_key_encrypted(&plain).unwrap());
+        let encrypted = generate_test_key("xtauri_key_encrypted_pem", "-m PEM -N secret");
+        assert!(Ssh::is_key_encrypted(&encrypted).unwrap());
+        assert!(Ssh::is_key_encrypted(Path::new("/invalid/key")).is_err());
+    }
+    #[tokio::test]
+    async fn connect_with_key_passphrase_required() {
+        let mut ssh = trusting_ssh();
+        let (host, user, _, _) = get_params();
+        let pkey = generate_test_key("xtauri_key_passphrase", "-N secret");
+        let r = ssh
+            .connect_with_key(&host, PORT, &user, pkey.to_str().unwrap(), None)
+            .await;
+        assert!(matches!(r, Err(SshError::PassphraseRequired(_))));
+    }
+    #[tokio::test]
+    async fn connect_negotiate() {
+        let (host, user, pass, _) = get_params();
+        let mut ssh = trusting_ssh();
+        let keys = ["/invalid/key".to_string()];
+        let r = ssh.connect(&host, PORT, &user, &keys, None, Some(&pass)).await;
+        assert_ne!(r.unwrap(), AuthMethod::None);
+        assert_eq!(user, ssh.run("whoami").unwrap());
+    }
+    #[tokio::test]
+    async fn connect_negotiate_key() {
+        let (host, user, _, _) = get_params();
+        let mut ssh = trusting_ssh();
+        let pkey = Ssh::private_key_path().to_string_lossy().to_string();
+        let r = ssh.connect(&host, PORT, &user, &[pkey], None, None).await;
+        assert!(matches!(
+            r.unwrap(),
+            AuthMethod::Agent | AuthMethod::PublicKey { .. }
+        ));
+    }
     #[tokio::test]
     async fn connect_with_host_wrong() {
-        let mut ssh = Ssh::new();
+        let mut ssh = trusting_ssh();
         let (_, user, pass, _) = get_params();
         let r = ssh
             .connect_with_password("example.com", PORT, &user, &pass)
@@ -821,7 +1582,7 @@ mod tests {
     }
     #[tokio::test]
     async fn run_command() {
-        let mut ssh = Ssh::new();
+        let mut ssh = trusting_ssh();
         let (host, user, pass, _) = get_params();
         let r = ssh.connect_with_password(&host, PORT, &user, &pass).await;
         assert!(r.is_ok());
@@ -867,7 +1628,7 @@ mod tests {
     #[tokio::test]
     async fn setup_ssh() {
         let (host, user, pass, _) = get_params();
-        assert!(Ssh::setup_ssh(&host, PORT, &user, &pass).await.is_ok());
+        assert!(Ssh::setup_ssh(&host, PORT, &user, &pass, |_: &HostKey| true).await.is_ok());
     }
 
     #[test]
@@ -876,7 +1637,7 @@ mod tests {
     }
     #[tokio::test]
     async fn mkdir_rmdir() {
-        let mut ssh = Ssh::new();
+        let mut ssh = trusting_ssh();
         let (host, user, pass, home) = get_params();
         let r = ssh.connect_with_password(&host, PORT, &user, &pass).await;
         assert!(r.is_ok());
@@ -890,7 +1651,7 @@ mod tests {
     }
     #[tokio::test]
     async fn create_delete() {
-        let mut ssh = Ssh::new();
+        let mut ssh = trusting_ssh();
         let (host, user, pass, home) = get_params();
         let r = ssh.connect_with_password(&host, PORT, &user, &pass).await;
         assert!(r.is_ok());
@@ -910,7 +1671,7 @@ mod tests {
     }
     #[tokio::test]
     async fn readdir() {
-        let mut ssh = Ssh::new();
+        let mut ssh = trusting_ssh();
         let (host, user, pass, home) = get_params();
         let r = ssh.connect_with_password(&host, PORT, &user, &pass).await;
         assert!(r.is_ok());
@@ -923,7 +1684,7 @@ mod tests {
     }
     #[tokio::test]
     async fn rename() {
-        let mut ssh = Ssh::new();
+        let mut ssh = trusting_ssh();
         let (host, user, pass, home) = get_params();
         let r = ssh.connect_with_password(&host, PORT, &user, &pass).await;
         assert!(r.is_ok());
@@ -939,10 +1700,352 @@ mod tests {
         assert!(ssh.sftp_delete(&format!("{home}/file1")).is_ok());
         assert!(ssh.sftp_stat(&format!("{home}/file1")).is_err());
     }
+    #[tokio::test]
+    async fn list_entries() {
+        let mut ssh = trusting_ssh();
+        let (host, user, pass, home) = get_params();
+        let r = ssh.connect_with_password(&host, PORT, &user, &pass).await;
+        assert!(r.is_ok());
+        let dir = format!("{home}/entries");
+        assert!(ssh.sftp_mkdir(&dir).is_ok());
+        assert!(ssh.sftp_save(&format!("{dir}/file"), "hello").is_ok());
+        assert!(ssh.sftp_mkdir(&format!("{dir}/sub")).is_ok());
+        assert!(ssh.run(&format!("ln -s file {dir}/link")).is_ok());
+
+        let entries = ssh.sftp_list(&dir).unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["sub", "file", "link"]);
+        assert_eq!(entries[1].kind, FileKind::File);
+        assert_eq!(entries[1].size, 5);
+        assert_eq!(entries[2].kind, FileKind::Symlink);
+        assert_eq!(entries[2].target.as_deref(), Some("file"));
+        let entry = ssh.sftp_entry(&format!("{dir}/sub")).unwrap();
+        assert_eq!(entry.kind, FileKind::Dir);
+        assert!(entry.mtime > 0);
+        assert!(ssh.sftp_delete(&dir).is_ok());
+    }
+    #[tokio::test]
+    async fn scp_progress() {
+        let mut ssh = trusting_ssh();
+        let (host, user, pass, home) = get_params();
+        let r = ssh.connect_with_password(&host, PORT, &user, &pass).await;
+        assert!(r.is_ok());
+        let local = std::env::temp_dir().join("xtauri_scp");
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| i as u8).collect();
+        std::fs::write(&local, &data).unwrap();
+        let local = local.to_string_lossy().to_string();
+        let remote = format!("{home}/xtauri_scp");
+
+        let (mut progress, events) = recorded(1);
+        let session = ssh.connected().unwrap().clone();
+        let control = Control::default();
+        assert!(transfer::upload(&session, &local, &remote, &mut progress, &control).is_ok());
+        assert_eq!(ssh.sftp_stat(&remote).unwrap().size, Some(1_000_000));
+        let last = events.lock().unwrap().last().cloned().unwrap();
+        assert_eq!((last.bytes, last.total), (1_000_000, 1_000_000));
+
+        let (mut progress, _) = recorded(2);
+        std::fs::remove_file(&local).unwrap();
+        assert!(transfer::download(&session, &remote, &local, &mut progress, &control).is_ok());
+        assert_eq!(progress.bytes(), 1_000_000);
+        assert_eq!(std::fs::read(&local).unwrap(), data);
+        assert!(ssh.sftp_delete(&remote).is_ok());
+        std::fs::remove_file(&local).unwrap();
+    }
+    #[tokio::test]
+    async fn sftp_resume() {
+        let mut ssh = trusting_ssh();
+        let (host, user, pass, home) = get_params();
+        let r = ssh.connect_with_password(&host, PORT, &user, &pass).await;
+        assert!(r.is_ok());
+        let local = std::env::temp_dir().join("xtauri_resume");
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&local, &data).unwrap();
+        let local = local.to_string_lossy().to_string();
+        let remote = format!("{home}/xtauri_resume");
+        let session = ssh.connected().unwrap().clone();
+        let control = Control::default();
+        let options = ResumeOptions { checksum: true };
+
+        // a remote partial copy started from the local file is continued
+        let part = format!("{local}.src");
+        std::fs::write(&part, &data[..400_000]).unwrap();
+        let (mut progress, _) = recorded(1);
+        assert!(transfer::upload(&session, &part, &remote, &mut progress, &control).is_ok());
+        std::fs::remove_file(&part).unwrap();
+        let modified = std::fs::metadata(&local).unwrap().modified().unwrap();
+        let mtime = modified.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let sftp = ssh.sftp().unwrap();
+        transfer::write_remote_record(sftp, &remote, (1_000_000, mtime)).unwrap();
+        let (mut progress, events) = recorded(2);
+        let r = transfer::sftp_upload(&session, &local, &remote, &mut progress, &control, options);
+        assert!(r.is_ok());
+        assert_eq!(events.lock().unwrap()[0].bytes, 400_000);
+        let stat = ssh.sftp_stat(&remote).unwrap();
+        assert_eq!(stat.size, Some(1_000_000));
+        let source = (1_000_000, stat.mtime.unwrap());
+
+        // a local partial copy is continued from its size
+        let file = std::fs::OpenOptions::new().write(true).open(&local).unwrap();
+        file.set_len(600_000).unwrap();
+        drop(file);
+        transfer::write_local_record(&local, source).unwrap();
+        let (mut progress, events) = recorded(3);
+        let r = transfer::sftp_download(&session, &remote, &local, &mut progress, &control, options);
+        assert!(r.is_ok());
+        assert_eq!(events.lock().unwrap()[0].bytes, 600_000);
+        assert_eq!(std::fs::read(&local).unwrap(), data);
+
+        // a damaged copy fails the checksum and starts over
+        let mut damaged = data.clone();
+        damaged[10] ^= 1;
+        std::fs::write(&local, &damaged[..500_000]).unwrap();
+        transfer::write_local_record(&local, source).unwrap();
+        let (mut progress, events) = recorded(4);
+        let r = transfer::sftp_download(&session, &remote, &local, &mut progress, &control, options);
+        assert!(r.is_ok());
+        assert_eq!(events.lock().unwrap()[0].bytes, 0);
+        assert_eq!(std::fs::read(&local).unwrap(), data);
+
+        assert!(ssh.sftp_delete(&remote).is_ok());
+        std::fs::remove_file(&local).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn directory_tree() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+        let mut ssh = trusting_ssh();
+        let (host, user, pass, home) = get_params();
+        let r = ssh.connect_with_password(&host, PORT, &user, &pass).await;
+        assert!(r.is_ok());
+        let local = std::env::temp_dir().join("xtauri_dir");
+        let _ = std::fs::remove_dir_all(&local);
+        std::fs::create_dir_all(local.join("sub")).unwrap();
+        std::fs::write(local.join("sub/script"), b"#!/bin/sh\n").unwrap();
+        let mode = std::fs::Permissions::from_mode(0o750);
+        std::fs::set_permissions(local.join("sub/script"), mode).unwrap();
+        std::fs::write(local.join("data"), vec![7u8; 100_000]).unwrap();
+        symlink("sub/script", local.join("link")).unwrap();
+        let localpath = local.to_string_lossy().to_string();
+        let remote = format!("{home}/xtauri_dir");
+        let session = ssh.connected().unwrap().clone();
+        let control = Control::default();
+        let (mut progress, events) = recorded(1);
+
+        // links are kept as links, files keep their mode and mtime
+        let policy = SymlinkPolicy::Link;
+        let r = tree::upload(&session, &localpath, &remote, &mut progress, &control, policy);
+        assert!(r.is_ok());
+        let last = events.lock().unwrap().last().unwrap().clone();
+        assert_eq!((last.bytes, last.total), (100_010, 100_010));
+        assert_eq!((last.files, last.files_done), (2, 2));
+        let stat = ssh.sftp_stat(&format!("{remote}/sub/script")).unwrap();
+        let modified = std::fs::metadata(local.join("sub/script")).unwrap().modified();
+        let mtime = modified.unwrap().duration_since(std::time::UNIX_EPOCH).unwrap();
+        assert_eq!(stat.perm.unwrap() & 0o7777, 0o750);
+        assert_eq!(stat.mtime, Some(mtime.as_secs()));
+        let target = ssh.sftp_readlink(&format!("{remote}/link")).unwrap();
+        assert_eq!(target, "sub/script");
+
+        // followed links come back as files
+        let copy = std::env::temp_dir().join("xtauri_dir_copy");
+        let _ = std::fs::remove_dir_all(&copy);
+        let copypath = copy.to_string_lossy().to_string();
+        let mut progress = Progress::new(2, |_| {});
+        let policy = SymlinkPolicy::Follow;
+        let r = tree::download(&session, &remote, &copypath, &mut progress, &control, policy);
+        assert!(r.is_ok());
+        assert_eq!(std::fs::read(copy.join("link")).unwrap(), b"#!/bin/sh\n");
+        assert_eq!(std::fs::read(copy.join("data")).unwrap(), vec![7u8; 100_000]);
+        let meta = std::fs::metadata(copy.join("sub/script")).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o750);
+
+        let r = transfer::exec(&session, &format!("rm -rf {}", command::quote(&remote)));
+        assert!(r.is_ok());
+        std::fs::remove_dir_all(&local).unwrap();
+        std::fs::remove_dir_all(&copy).unwrap();
+    }
+
+    #[tokio::test]
+    async fn forward_local() {
+        let mut ssh = trusting_ssh();
+        let (host, user, pass, _) = get_params();
+        ssh.connect_with_password(&host, PORT, &user, &pass).await.unwrap();
+
+        // the server own ssh port, answered with its banner
+        let id = ssh.forward_local("127.0.0.1", 0, "localhost", PORT).unwrap();
+        let info = ssh.forward_list()[0].clone();
+        assert_eq!(info.id, id);
+        let mut tcp = TcpStream::connect(("127.0.0.1", info.bind_port)).unwrap();
+        let mut banner = [0; 7];
+        tcp.read_exact(&mut banner).unwrap();
+        assert_eq!(&banner, b"SSH-2.0");
+
+        let info = ssh.forward_list()[0].clone();
+        assert_eq!(info.connections, 1);
+        assert!(info.received >= 7);
+        assert!(ssh.forward_remove(id).is_ok());
+        assert!(ssh.forward_list().is_empty());
+        assert!(ssh.forward_remove(id).is_err());
+    }
+
+    #[tokio::test]
+    async fn forward_dynamic() {
+        let mut ssh = trusting_ssh();
+        let (host, user, pass, _) = get_params();
+        ssh.connect_with_password(&host, PORT, &user, &pass).await.unwrap();
+
+        let id = ssh.forward_dynamic("127.0.0.1", 0).unwrap();
+        let port = ssh.forward_list()[0].bind_port;
+        let mut tcp = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        // no auth, CONNECT localhost:22
+        tcp.write_all(&[5, 1, 0, 5, 1, 0, 3, 9]).unwrap();
+        tcp.write_all(b"localhost").unwrap();
+        tcp.write_all(&PORT.to_be_bytes()).unwrap();
+        let mut reply = [0; 12];
+        tcp.read_exact(&mut reply).unwrap();
+        assert_eq!(reply[..4], [5, 0, 5, 0]);
+        let mut banner = [0; 7];
+        tcp.read_exact(&mut banner).unwrap();
+        assert_eq!(&banner, b"SSH-2.0");
+        assert!(ssh.forward_remove(id).is_ok());
+    }
+
+    #[tokio::test]
+    async fn forward_remote() {
+        let mut ssh = trusting_ssh();
+        let (host, user, pass, _) = get_params();
+        ssh.connect_with_password(&host, PORT, &user, &pass).await.unwrap();
+
+        // the remote side connects back to a local listener
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let local_port = listener.local_addr().unwrap().port();
+        let id = ssh.forward_remote("127.0.0.1", 0, "127.0.0.1", local_port).unwrap();
+        let remote_port = ssh.forward_list()[0].bind_port;
+        assert!(remote_port > 0);
+        let accepted = thread::spawn(move || {
+            let (mut tcp, _) = listener.accept().unwrap();
+            tcp.write_all(b"hello").unwrap();
+        });
+        let cmd = format!("exec 3<>/dev/tcp/127.0.0.1/{remote_port}; head -c 5 <&3");
+        let output = ssh.run(&format!("bash -c '{cmd}'")).unwrap();
+        accepted.join().unwrap();
+        assert_eq!(output.trim(), "hello");
+        assert_eq!(ssh.forward_list()[0].connections, 1);
+        assert!(ssh.forward_remove(id).is_ok());
+    }
+
+    #[tokio::test]
+    async fn forward_agent() {
+        let (host, user, pass, _) = get_params();
+        let (sock, pid) = spawn_agent();
+        let pkey = Ssh::private_key_path();
+        command::run(&format!("SSH_AUTH_SOCK={sock} ssh-add {}", pkey.display()));
+
+        let mut ssh = trusting_ssh();
+        ssh.set_agent_path(&sock);
+        ssh.set_agent_forwarding(true);
+        ssh.connect_with_password(&host, PORT, &user, &pass).await.unwrap();
+        // the remote ssh-add lists the local key through the relay
+        let output = shell_output(&mut ssh, "ssh-add -l");
+        command::run(&format!("SSH_AGENT_PID={pid} ssh-agent -k"));
+        assert!(output.contains("SHA256:"), "{output}");
+    }
+
+    // run a line in a new terminal and collect everything it prints
+    fn shell_output(ssh: &mut Ssh, line: &str) -> String {
+        let id = ssh.channel_shell().unwrap();
+        shell_run(ssh, id, line)
+    }
+    fn shell_run(ssh: &mut Ssh, id: u32, line: &str) -> String {
+        let pty = ssh.channel_pty(id).unwrap();
+        let mut pty = pty.lock().unwrap();
+        let line = format!("{line}; exit\n");
+        tunnel::write_all_nonblocking(&mut *pty, line.as_bytes()).unwrap();
+
+        let mut output = String::new();
+        let mut buf = [0; 1024];
+        let start = time::Instant::now();
+        while !pty.eof() && start.elapsed().as_secs() < 10 {
+            match pty.read(&mut buf) {
+                Ok(n) => output.push_str(&String::from_utf8_lossy(&buf[..n])),
+                Err(_) => thread::sleep(Duration::from_millis(WAIT_MS)),
+            }
+        }
+        output
+    }
+
+    #[tokio::test]
+    async fn terminal_env_and_startup() {
+        let mut ssh = trusting_ssh();
+        let (host, user, pass, _) = get_params();
+        let env = BTreeMap::from([("XTAURI_TEST".to_string(), "it's set".to_string())]);
+        ssh.set_env(env);
+        ssh.set_startup("/tmp", "echo started");
+        ssh.connect_with_password(&host, PORT, &user, &pass).await.unwrap();
+
+        let output = shell_output(&mut ssh, "echo \"[$XTAURI_TEST]\"; pwd");
+        assert!(output.contains("[it's set]"), "{output}");
+        assert!(output.contains("\nstarted"), "{output}");
+        assert!(output.contains("/tmp"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn reconnect_after_drop() {
+        let mut ssh = trusting_ssh();
+        let (host, user, pass, _) = get_params();
+        ssh.connect_with_password(&host, PORT, &user, &pass).await.unwrap();
+        let id = ssh.channel_shell().unwrap();
+        ssh.channel_shell_size(id, 100, 30).unwrap();
+
+        let tcp = ssh.tcp.clone().unwrap();
+        tcp.lock().unwrap().shutdown(std::net::Shutdown::Both).unwrap();
+        assert!(ssh.run("whoami").is_err());
+
+        assert!(ssh.reconnect().is_ok());
+        assert_eq!(user, ssh.run("whoami").unwrap());
+        // the terminal keeps its id on the new connection
+        assert!(ssh.channel_pty(id).is_ok());
+        assert!(ssh.channel_shell_size(id, 120, 40).is_ok());
+    }
+
+    #[tokio::test]
+    async fn keepalive() {
+        let mut ssh = trusting_ssh();
+        let (host, user, pass, _) = get_params();
+        ssh.set_keepalive(5, 0);
+        assert_eq!(ssh.keepalive(), (5, 3));
+        assert!(ssh.keepalive_send().is_err());
+        ssh.connect_with_password(&host, PORT, &user, &pass).await.unwrap();
+        assert!(ssh.keepalive_send().is_ok());
+        assert_eq!(user, ssh.run("whoami").unwrap());
+    }
+
+    #[tokio::test]
+    async fn terminal_exit_status() {
+        let mut ssh = trusting_ssh();
+        let (host, user, pass, _) = get_params();
+        ssh.connect_with_password(&host, PORT, &user, &pass).await.unwrap();
+
+        let id = ssh.channel_shell().unwrap();
+        shell_run(&mut ssh, id, "(exit 0); exit 3");
+        let exit = ssh.channel_exit(id).unwrap();
+        assert_eq!(exit.status, Some(3));
+        assert_eq!(exit.signal, None);
+        assert!(ssh.channel_pty(id).is_err());
+
+        // the session is still usable
+        let id = ssh.channel_shell().unwrap();
+        shell_run(&mut ssh, id, "kill -KILL $$");
+        let exit = ssh.channel_exit(id).unwrap();
+        assert_eq!(exit.signal.as_deref(), Some("KILL"));
+        assert_eq!(exit.status, None);
+    }
 
     #[tokio::test]
     async fn test_connect_with_password_via_jump() {
-        let mut ssh = Ssh::new();
+        let mut ssh = trusting_ssh();
         let (jump_host, jump_user, jump_pass) = get_jump_params();
         let (target_host, target_user, target_pass) = get_target_params();
 
@@ -986,7 +2089,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_connect_with_key_via_jump() {
-        let mut ssh = Ssh::new();
+        let mut ssh = trusting_ssh();
         let (jump_host, jump_user, _) = get_jump_params();
         let (target_host, target_user, _) = get_target_params();
 
@@ -998,7 +2101,7 @@ mod tests {
         ssh.set_jump_server_with_key(&jump_host, &jump_user, jump_key.to_str().unwrap());
 
         // Connect to target through jump server using key
-        let result = ssh.connect_with_key(&target_host, PORT, &target_user, target_key.to_str().unwrap()).await;
+        let result = ssh.connect_with_key(&target_host, PORT, &target_user, target_key.to_str().unwrap(), None).await;
         
         match result {
             Ok(_) => {
@@ -1026,9 +2129,82 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_connect_with_agent_via_jump() {
+        let mut ssh = trusting_ssh();
+        let (jump_host, jump_user, _) = get_jump_params();
+        let (target_host, target_user, _) = get_target_params();
+        let jump_port = env::var("TEST_JUMP_PORT").map_or(PORT, |p| p.parse().unwrap());
+
+        // no key or password, the jump host is authenticated with the agent
+        ssh.set_jumps(vec![Jump {
+            host: jump_host,
+            port: jump_port,
+            user: jump_user,
+            ..Default::default()
+        }]);
+        let r = ssh.connect_with_agent(&target_host, PORT, &target_user).await;
+        assert!(r.is_ok(), "{:?}", r);
+        assert_eq!(target_user, ssh.run("whoami").unwrap().trim());
+        assert!(ssh.disconnect().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connect_via_jump_chain() {
+        let mut ssh = trusting_ssh();
+        let (target_host, target_user, target_pass) = get_target_params();
+        let spec = env::var("TEST_PROXY_JUMP").unwrap_or_else(|_| "JumpServer,JumpServer2".to_string());
+
+        ssh.set_jumps(crate::settings::parse_proxy_jump(&spec, &target_user).unwrap());
+        let r = ssh.connect_with_password(&target_host, PORT, &target_user, &target_pass).await;
+        assert!(r.is_ok(), "{:?}", r);
+        assert_eq!(ssh.jump_sessions.len(), ssh.jumps.len());
+        assert_eq!(target_user, ssh.run("whoami").unwrap().trim());
+        assert!(ssh.disconnect().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_jump_chain_hop_error() {
+        let mut ssh = trusting_ssh();
+        let (jump_host, jump_user, jump_pass) = get_jump_params();
+        let (target_host, target_user, target_pass) = get_target_params();
+
+        // the first hop cannot reach the second one
+        ssh.set_jumps(vec![
+            Jump {
+                host: jump_host.clone(),
+                user: jump_user.clone(),
+                password: Some(jump_pass),
+                ..Default::default()
+            },
+            Jump {
+                host: "invalid-jump-host".to_string(),
+                user: jump_user,
+                ..Default::default()
+            },
+        ]);
+        let r = ssh.connect_with_password(&target_host, PORT, &target_user, &target_pass).await;
+        let error = r.unwrap_err().to_string();
+        assert!(error.starts_with(&format!("Jump server 1 ({jump_host}:22)")), "{error}");
+        assert!(error.contains("invalid-jump-host"));
+    }
+
+    #[tokio::test]
+    async fn connect_with_proxy_command() {
+        let mut ssh = trusting_ssh();
+        let (host, user, pass, _) = get_params();
+        let cmd = env::var("TEST_PROXY_COMMAND").unwrap_or_else(|_| "nc %h %p".to_string());
+
+        ssh.set_proxy_command(&cmd);
+        let r = ssh.connect_with_password(&host, PORT, &user, &pass).await;
+        assert!(r.is_ok(), "{:?}", r);
+        assert_eq!(user, ssh.run("whoami").unwrap().trim());
+        assert!(ssh.disconnect().is_ok());
+    }
+
     #[tokio::test]
     async fn test_jump_server_invalid_credentials() {
-        let mut ssh = Ssh::new();
+        let mut ssh = trusting_ssh();
         let (jump_host, jump_user, _) = get_jump_params();
         let (target_host, target_user, target_pass) = get_target_params();
 
@@ -1039,14 +2215,14 @@ mod tests {
         let result = ssh.connect_with_password(&target_host, PORT, &target_user, &target_pass).await;
         
         assert!(result.is_err());
-        let error = result.unwrap_err();
+        let error = result.unwrap_err().to_string();
         assert!(error.contains("Jump server authentication error") || error.contains("authentication"));
         println!("✅ Correctly failed with invalid jump server credentials: {}", error);
     }
 
     #[tokio::test]
     async fn test_jump_server_invalid_target() {
-        let mut ssh = Ssh::new();
+        let mut ssh = trusting_ssh();
         let (jump_host, jump_user, jump_pass) = get_jump_params();
         let (_, target_user, target_pass) = get_target_params();
 
@@ -1057,7 +2233,7 @@ mod tests {
         let result = ssh.connect_with_password("invalid-target-host", PORT, &target_user, &target_pass).await;
         
         assert!(result.is_err());
-        let error = result.unwrap_err();
+        let error = result.unwrap_err().to_string();
         assert!(error.contains("tunnel") || error.contains("connection") || error.contains("handshake"));
         println!("✅ Correctly failed with invalid target host: {}", error);
     }
@@ -1070,11 +2246,11 @@ mod tests {
         let (target_host, target_user, target_pass) = get_target_params();
 
         // Test direct connection (existing functionality)
-        let mut direct_ssh = Ssh::new();
+        let mut direct_ssh = trusting_ssh();
         let direct_result = direct_ssh.connect_with_password(&direct_host, PORT, &direct_user, &direct_pass).await;
         
         // Test jump connection
-        let mut jump_ssh = Ssh::new();
+        let mut jump_ssh = trusting_ssh();
         jump_ssh.set_jump_server(&jump_host, &jump_user, &jump_pass);
         let jump_result = jump_ssh.connect_with_password(&target_host, PORT, &target_user, &target_pass).await;
 
@@ -1104,7 +2280,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_jump_server_multiple_operations() {
-        let mut ssh = Ssh::new();
+        let mut ssh = trusting_ssh();
         let (jump_host, jump_user, jump_pass) = get_jump_params();
         let (target_host, target_user, target_pass) = get_target_params();
 
@@ -1163,6 +2339,9 @@ pub fn setup_test_env() {
     // export TEST_JUMP_HOST="your-jump-server"
     // export TEST_JUMP_USER="jump-user"
     // export TEST_JUMP_PASS="jump-password"
+    // export TEST_JUMP_PORT="22"
+    // export TEST_PROXY_COMMAND="nc %h %p"
+    // export TEST_PROXY_JUMP="jump-user@jump-server,jump-user@second-jump-server:2222"
     // export TEST_TARGET_HOST="target-node"
     // export TEST_TARGET_USER="target-user"
     // export TEST_TARGET_PASS="target-password"
@@ -0,0 +1,1058 @@
+use sha2::{Digest, Sha256};
+use ssh2::{FileStat, OpenFlags, OpenType, Session, Sftp};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use std::{thread, time};
+
+use super::command;
+use super::error::SshError;
+use super::progress::{Progress, TransferProgress};
+use super::ssh::Ssh;
+use super::tree::{self, SymlinkPolicy};
+
+const WAIT_MS: u64 = 20;
+
+// transfers running at the same time when not configured
+pub const CONCURRENCY: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransferState {
+    Queued,
+    Running,
+    Paused,
+    Done,
+    Cancelled,
+    Failed,
+}
+
+// transfers with resume options go over sftp and continue a partial copy,
+// checksum compares the sha256 of the part already copied on both sides
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ResumeOptions {
+    #[serde(default)]
+    pub checksum: bool,
+}
+
+// how a queued transfer copies, a symlink policy makes it a directory transfer
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TransferOptions {
+    pub resume: Option<ResumeOptions>,
+    pub symlinks: Option<SymlinkPolicy>,
+}
+
+// a queued transfer as listed by list_transfers, id is the session
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct TransferInfo {
+    pub transfer: u32,
+    pub id: u32,
+    pub kind: Direction,
+    pub localpath: String,
+    pub remotepath: String,
+    pub resume: Option<ResumeOptions>,
+    pub symlinks: Option<SymlinkPolicy>,
+    pub state: TransferState,
+    pub bytes: u64,
+    pub total: u64,
+    pub error: Option<SshError>,
+}
+
+// pause and cancel requests, checked by the copy loops between blocks
+#[derive(Default)]
+pub struct Control {
+    cancel: AtomicBool,
+    pause: AtomicBool,
+}
+
+impl Control {
+    // waits while paused, an error once cancelled
+    pub fn proceed(&self) -> Result<(), SshError> {
+        while self.pause.load(Ordering::SeqCst) && !self.cancel.load(Ordering::SeqCst) {
+            thread::sleep(time::Duration::from_millis(WAIT_MS));
+        }
+        if self.cancel.load(Ordering::SeqCst) {
+            return Err(SshError::Cancelled("Transfer cancelled".to_string()));
+        }
+        Ok(())
+    }
+}
+
+pub struct Job {
+    transfer: u32,
+    id: u32,
+    kind: Direction,
+    localpath: String,
+    remotepath: String,
+    resume: Option<ResumeOptions>,
+    symlinks: Option<SymlinkPolicy>,
+    control: Control,
+    bytes: AtomicU64,
+    total: AtomicU64,
+    status: Mutex<Status>,
+}
+
+struct Status {
+    state: TransferState,
+    // paused after it started, it keeps its channel
+    started: bool,
+    error: Option<SshError>,
+}
+
+impl Job {
+    pub fn transfer(&self) -> u32 {
+        self.transfer
+    }
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+    pub fn kind(&self) -> Direction {
+        self.kind
+    }
+    pub fn localpath(&self) -> &str {
+        &self.localpath
+    }
+    pub fn remotepath(&self) -> &str {
+        &self.remotepath
+    }
+    pub fn set_progress(&self, progress: &TransferProgress) {
+        self.bytes.store(progress.bytes, Ordering::SeqCst);
+        self.total.store(progress.total, Ordering::SeqCst);
+    }
+    pub fn info(&self) -> TransferInfo {
+        let status = self.status.lock().unwrap();
+        TransferInfo {
+            transfer: self.transfer,
+            id: self.id,
+            kind: self.kind,
+            localpath: self.localpath.clone(),
+            remotepath: self.remotepath.clone(),
+            resume: self.resume,
+            symlinks: self.symlinks,
+            state: status.state,
+            bytes: self.bytes.load(Ordering::SeqCst),
+            total: self.total.load(Ordering::SeqCst),
+            error: status.error.clone(),
+        }
+    }
+    // copy the file on a channel of its own, the session mutex is not held
+    pub fn run(&self, session: &Session, progress: &mut Progress) -> Result<(), SshError> {
+        let control = &self.control;
+        if let Some(symlinks) = self.symlinks {
+            return match self.kind {
+                Direction::Upload => tree::upload(
+                    session,
+                    &self.localpath,
+                    &self.remotepath,
+                    progress,
+                    control,
+                    symlinks,
+                ),
+                Direction::Download => tree::download(
+                    session,
+                    &self.remotepath,
+                    &self.localpath,
+                    progress,
+                    control,
+                    symlinks,
+                ),
+            };
+        }
+        if let Some(options) = self.resume {
+            return match self.kind {
+                Direction::Upload => sftp_upload(
+                    session,
+                    &self.localpath,
+                    &self.remotepath,
+                    progress,
+                    control,
+                    options,
+                ),
+                Direction::Download => sftp_download(
+                    session,
+                    &self.remotepath,
+                    &self.localpath,
+                    progress,
+                    control,
+                    options,
+                ),
+            };
+        }
+        match self.kind {
+            Direction::Upload => upload(
+                session,
+                &self.localpath,
+                &self.remotepath,
+                progress,
+                &self.control,
+            ),
+            Direction::Download => download(
+                session,
+                &self.remotepath,
+                &self.localpath,
+                progress,
+                &self.control,
+            ),
+        }
+    }
+}
+
+// the transfer queue of all sessions, next_jobs hands out queued jobs
+// while fewer than concurrency are running
+pub struct Transfers {
+    jobs: Mutex<BTreeMap<u32, Arc<Job>>>,
+    next_transfer: AtomicU32,
+    concurrency: AtomicUsize,
+}
+
+impl Default for Transfers {
+    fn default() -> Self {
+        Self {
+            jobs: Mutex::new(BTreeMap::new()),
+            next_transfer: AtomicU32::new(0),
+            concurrency: AtomicUsize::new(CONCURRENCY),
+        }
+    }
+}
+
+impl Transfers {
+    // a transfer id for a transfer that does not go through the queue
+    pub fn next_transfer(&self) -> u32 {
+        self.next_transfer.fetch_add(1, Ordering::SeqCst) + 1
+    }
+    pub fn add(
+        &self,
+        id: u32,
+        kind: Direction,
+        localpath: &str,
+        remotepath: &str,
+        options: TransferOptions,
+    ) -> TransferInfo {
+        let job = Job {
+            transfer: self.next_transfer(),
+            id,
+            kind,
+            localpath: localpath.to_string(),
+            remotepath: remotepath.to_string(),
+            resume: options.resume,
+            symlinks: options.symlinks,
+            control: Control::default(),
+            bytes: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            status: Mutex::new(Status {
+                state: TransferState::Queued,
+                started: false,
+                error: None,
+            }),
+        };
+        let info = job.info();
+        self.jobs
+            .lock()
+            .unwrap()
+            .insert(job.transfer, Arc::new(job));
+        info
+    }
+    pub fn list(&self) -> Vec<TransferInfo> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .map(|j| j.info())
+            .collect()
+    }
+    pub fn set_concurrency(&self, concurrency: usize) {
+        self.concurrency.store(concurrency.max(1), Ordering::SeqCst);
+    }
+    // queued jobs that can start now, already marked running
+    pub fn next_jobs(&self) -> Vec<Arc<Job>> {
+        let jobs = self.jobs.lock().unwrap();
+        let mut running = jobs
+            .values()
+            .filter(|j| j.status.lock().unwrap().started)
+            .filter(|j| !finished(j.status.lock().unwrap().state))
+            .count();
+        let mut next = Vec::new();
+        for job in jobs.values() {
+            if running >= self.concurrency.load(Ordering::SeqCst) {
+                break;
+            }
+            let mut status = job.status.lock().unwrap();
+            if status.state == TransferState::Queued {
+                status.state = TransferState::Running;
+                status.started = true;
+                running += 1;
+                next.push(Arc::clone(job));
+            }
+        }
+        next
+    }
+    pub fn finish(&self, job: &Job, result: Result<(), SshError>) -> TransferInfo {
+        {
+            let mut status = job.status.lock().unwrap();
+            status.state = match result {
+                Ok(_) => TransferState::Done,
+                Err(SshError::Cancelled(_)) => TransferState::Cancelled,
+                Err(e) => {
+                    status.error = Some(e);
+                    TransferState::Failed
+                }
+            };
+        }
+        job.info()
+    }
+    pub fn pause(&self, transfer: u32) -> Result<TransferInfo, SshError> {
+        let job = self.job(transfer)?;
+        {
+            let mut status = job.status.lock().unwrap();
+            if status.state == TransferState::Queued || status.state == TransferState::Running {
+                job.control.pause.store(true, Ordering::SeqCst);
+                status.state = TransferState::Paused;
+            }
+        }
+        Ok(job.info())
+    }
+    // a paused queued job waits for its turn again
+    pub fn resume(&self, transfer: u32) -> Result<TransferInfo, SshError> {
+        let job = self.job(transfer)?;
+        {
+            let mut status = job.status.lock().unwrap();
+            if status.state == TransferState::Paused {
+                job.control.pause.store(false, Ordering::SeqCst);
+                status.state = if status.started {
+                    TransferState::Running
+                } else {
+                    TransferState::Queued
+                };
+            }
+        }
+        Ok(job.info())
+    }
+    // a running job stops at its next block and is finished by its thread
+    pub fn cancel(&self, transfer: u32) -> Result<TransferInfo, SshError> {
+        let job = self.job(transfer)?;
+        {
+            let mut status = job.status.lock().unwrap();
+            if !finished(status.state) {
+                job.control.cancel.store(true, Ordering::SeqCst);
+                if !status.started {
+                    status.state = TransferState::Cancelled;
+                }
+            }
+        }
+        Ok(job.info())
+    }
+    // queue a failed or cancelled transfer again, with resume options
+    // it continues where it stopped
+    pub fn retry(&self, transfer: u32) -> Result<TransferInfo, SshError> {
+        let job = self.job(transfer)?;
+        {
+            let mut status = job.status.lock().unwrap();
+            if status.state == TransferState::Failed || status.state == TransferState::Cancelled {
+                job.control.cancel.store(false, Ordering::SeqCst);
+                job.control.pause.store(false, Ordering::SeqCst);
+                *status = Status {
+                    state: TransferState::Queued,
+                    started: false,
+                    error: None,
+                };
+            }
+        }
+        Ok(job.info())
+    }
+    // forget the finished transfers
+    pub fn clear(&self) {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, j| !finished(j.status.lock().unwrap().state));
+    }
+    // the queued transfers of a closed session
+    pub fn cancel_session(&self, id: u32) {
+        let jobs: Vec<u32> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|j| j.id == id)
+            .map(|j| j.transfer)
+            .collect();
+        for transfer in jobs {
+            let _ = self.cancel(transfer);
+        }
+    }
+    fn job(&self, transfer: u32) -> Result<Arc<Job>, SshError> {
+        match self.jobs.lock().unwrap().get(&transfer) {
+            None => Err(SshError::Io(format!("Transfer {transfer} not found"))),
+            Some(o) => Ok(Arc::clone(o)),
+        }
+    }
+}
+
+fn finished(state: TransferState) -> bool {
+    matches!(
+        state,
+        TransferState::Done | TransferState::Cancelled | TransferState::Failed
+    )
+}
+
+pub fn download(
+    session: &Session,
+    remotepath: &str,
+    localpath: &str,
+    progress: &mut Progress,
+    control: &Control,
+) -> Result<(), SshError> {
+    println!("downloading: {remotepath}");
+    let (mut channel, stat) = match Ssh::retry(|| session.scp_recv(Path::new(remotepath))) {
+        Err(e) => return Err(SshError::Channel(format!("Cannot open scp channel: {}", e))),
+        Ok(o) => o,
+    };
+    let size = stat.size();
+    println!("remote file size: {}", size);
+    let f = File::create(localpath)?;
+    let mut f = BufWriter::new(f);
+    let mut buffer = [0; 16000];
+    progress.start(size, 0);
+    while progress.bytes() < size {
+        control.proceed()?;
+        match channel.read(&mut buffer[..]) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(time::Duration::from_millis(WAIT_MS));
+            }
+            Err(e) => {
+                println!("error: {:?}", e);
+                return Err(SshError::Channel(e.to_string()));
+            }
+            Ok(0) => {
+                let e = format!("Download of {remotepath} ended at {}", progress.bytes());
+                return Err(SshError::Channel(e));
+            }
+            Ok(n) => {
+                // scp sends a status byte after the file
+                let n = n.min((size - progress.bytes()) as usize);
+                f.write_all(&buffer[..n])?;
+                progress.add(n);
+            }
+        }
+    }
+    f.flush()?;
+    progress.finish();
+    println!("written: {}", progress.bytes());
+    Ok(())
+}
+
+pub fn upload(
+    session: &Session,
+    localpath: &str,
+    remotepath: &str,
+    progress: &mut Progress,
+    control: &Control,
+) -> Result<(), SshError> {
+    println!("uploading: {localpath} to {remotepath}");
+    let size = std::fs::metadata(localpath)?.len();
+    let path = Path::new(remotepath);
+    let mut channel = match Ssh::retry(|| session.scp_send(path, 0o644, size, None)) {
+        Err(e) => return Err(SshError::Channel(format!("Cannot open scp channel: {}", e))),
+        Ok(o) => o,
+    };
+    println!("file size: {}", size);
+    let f = File::open(localpath)?;
+    let mut f = BufReader::new(f);
+    let mut buffer = [0; 16000];
+    progress.start(size, 0);
+    loop {
+        control.proceed()?;
+        let n = f.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        let mut data = &buffer[..n];
+        while !data.is_empty() {
+            match channel.write(data) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(time::Duration::from_millis(WAIT_MS));
+                }
+                Err(e) => {
+                    println!("error: {:?}", e);
+                    return Err(SshError::Channel(e.to_string()));
+                }
+                Ok(n) => {
+                    data = &data[n..];
+                    progress.add(n);
+                }
+            }
+        }
+    }
+    // the file is complete once the server acknowledged the end
+    let closed = Ssh::retry(|| channel.send_eof())
+        .and_then(|_| Ssh::retry(|| channel.wait_eof()))
+        .and_then(|_| Ssh::retry(|| channel.close()))
+        .and_then(|_| Ssh::retry(|| channel.wait_close()));
+    if let Err(e) = closed {
+        return Err(SshError::Channel(format!(
+            "Cannot finish upload of {remotepath}: {e}"
+        )));
+    }
+    progress.finish();
+    println!("written: {}", progress.bytes());
+    Ok(())
+}
+
+// size and mtime in seconds of a file
+type Version = (u64, u64);
+
+// how much of an earlier copy can be kept, 0 starts over, recorded is the source
+// version the partial copy was started from, a complete copy gets the size and
+// mtime of the source and is kept whole, the mtimes always come from the source
+// side so clock skew between the machines does not matter
+pub fn resume_offset(source: Version, partial: Option<Version>, recorded: Option<Version>) -> u64 {
+    match (partial, recorded) {
+        (Some(copy), _) if copy == source => source.0,
+        (Some((size, _)), Some(recorded)) if recorded == source && size <= source.0 => size,
+        _ => 0,
+    }
+}
+
+// first word of a record, a file of that name that does not start with it
+// belongs to the user and is never overwritten or removed
+const RECORD_MAGIC: &str = "xtauri-resume";
+
+// an unfinished copy has its source version in a hidden file next to it,
+// remote paths are separated by /, local ones on windows also by \
+pub fn record_path(path: &str) -> String {
+    let (dir, name) = match path.rfind(['/', '\\']) {
+        None => ("", path),
+        Some(i) => path.split_at(i + 1),
+    };
+    format!("{dir}.{name}.{RECORD_MAGIC}")
+}
+
+fn parse_record(text: &str) -> Option<Version> {
+    let mut fields = text.split_whitespace();
+    if fields.next()? != RECORD_MAGIC {
+        return None;
+    }
+    let mut numbers = fields.map(|f| f.parse::<u64>().ok());
+    Some((numbers.next()??, numbers.next()??))
+}
+
+fn format_record(source: Version) -> String {
+    format!("{RECORD_MAGIC} {} {}\n", source.0, source.1)
+}
+
+fn not_a_record(record: &str) -> SshError {
+    SshError::Io(format!("{record} exists and is not a resume record"))
+}
+
+fn read_local_record(path: &str) -> Option<Version> {
+    parse_record(&std::fs::read_to_string(record_path(path)).ok()?)
+}
+
+pub fn write_local_record(path: &str, source: Version) -> Result<(), SshError> {
+    let record = record_path(path);
+    if Path::new(&record).exists() && read_local_record(path).is_none() {
+        return Err(not_a_record(&record));
+    }
+    std::fs::write(&record, format_record(source))?;
+    Ok(())
+}
+
+fn remove_local_record(path: &str) {
+    if read_local_record(path).is_some() {
+        let _ = std::fs::remove_file(record_path(path));
+    }
+}
+
+fn read_remote_record(sftp: &Sftp, path: &str) -> Option<Version> {
+    let mut file = Ssh::retry(|| sftp.open(Path::new(&record_path(path)))).ok()?;
+    let mut text = Vec::new();
+    let mut buffer = [0; 64];
+    loop {
+        match file.read(&mut buffer) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(time::Duration::from_millis(WAIT_MS));
+            }
+            Err(_) => return None,
+            Ok(0) => break,
+            Ok(n) => text.extend_from_slice(&buffer[..n]),
+        }
+    }
+    parse_record(&String::from_utf8_lossy(&text))
+}
+
+pub fn write_remote_record(sftp: &Sftp, path: &str, source: Version) -> Result<(), SshError> {
+    let record = record_path(path);
+    let exists = Ssh::retry(|| sftp.lstat(Path::new(&record))).is_ok();
+    if exists && read_remote_record(sftp, path).is_none() {
+        return Err(not_a_record(&record));
+    }
+    let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+    let open = Ssh::retry(|| sftp.open_mode(Path::new(&record), flags, 0o644, OpenType::File));
+    let mut file = match open {
+        Err(e) => return Err(SshError::Sftp(format!("Cannot create {record}: {e}"))),
+        Ok(o) => o,
+    };
+    let text = format_record(source);
+    let mut data = text.as_bytes();
+    while !data.is_empty() {
+        match file.write(data) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(time::Duration::from_millis(WAIT_MS));
+            }
+            Err(e) => return Err(SshError::Sftp(format!("Cannot write {record}: {e}"))),
+            Ok(n) => data = &data[n..],
+        }
+    }
+    if let Err(e) = Ssh::retry(|| file.close()) {
+        return Err(SshError::Sftp(format!("Cannot close {record}: {e}")));
+    }
+    Ok(())
+}
+
+fn remove_remote_record(sftp: &Sftp, path: &str) {
+    if read_remote_record(sftp, path).is_some() {
+        let _ = Ssh::retry(|| sftp.unlink(Path::new(&record_path(path))));
+    }
+}
+
+fn local_version(path: &str) -> std::io::Result<Version> {
+    let meta = std::fs::metadata(path)?;
+    let mtime = match meta.modified()?.duration_since(UNIX_EPOCH) {
+        Err(_) => 0,
+        Ok(o) => o.as_secs(),
+    };
+    Ok((meta.len(), mtime))
+}
+
+fn remote_version(stat: &FileStat) -> Version {
+    (stat.size.unwrap_or(0), stat.mtime.unwrap_or(0))
+}
+
+// the offset to continue from, 0 unless the copied part has the same sha256 on both sides
+fn checked_offset(
+    session: &Session,
+    offset: u64,
+    options: ResumeOptions,
+    localpath: &str,
+    remotepath: &str,
+) -> u64 {
+    if offset == 0 || !options.checksum {
+        return offset;
+    }
+    match (
+        local_checksum(localpath, offset),
+        remote_checksum(session, remotepath, offset),
+    ) {
+        (Ok(local), Ok(remote)) if local == remote => offset,
+        (Ok(_), Ok(_)) => {
+            println!("{remotepath} differs in the first {offset} bytes, starting over");
+            0
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            println!("cannot compare {remotepath}, starting over: {e}");
+            0
+        }
+    }
+}
+
+fn local_checksum(path: &str, len: u64) -> Result<String, SshError> {
+    let mut file = File::open(path)?.take(len);
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn remote_checksum(session: &Session, path: &str, len: u64) -> Result<String, SshError> {
+    let cmd = format!("head -c {len} {} | sha256sum", command::quote(path));
+    let output = exec(session, &cmd)?;
+    match output.split_whitespace().next() {
+        Some(sum) if sum.len() == 64 => Ok(sum.to_string()),
+        _ => Err(SshError::Channel(format!("No sha256sum of {path}"))),
+    }
+}
+
+// run a command on a channel of its own, returns its stdout
+pub fn exec(session: &Session, cmd: &str) -> Result<String, SshError> {
+    let mut channel = match Ssh::retry(|| session.channel_session()) {
+        Err(e) => return Err(SshError::Channel(format!("Cannot open channel: {e}"))),
+        Ok(o) => o,
+    };
+    if let Err(e) = Ssh::retry(|| channel.exec(cmd)) {
+        return Err(SshError::Channel(format!("Cannot run {cmd}: {e}")));
+    }
+    let mut output = Vec::new();
+    let mut buffer = [0; 256];
+    loop {
+        match channel.read(&mut buffer) {
+            Ok(0) if channel.eof() => break,
+            Ok(0) => thread::sleep(time::Duration::from_millis(WAIT_MS)),
+            Ok(n) => output.extend_from_slice(&buffer[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(time::Duration::from_millis(WAIT_MS));
+            }
+            Err(e) => return Err(SshError::Channel(format!("Cannot read {cmd}: {e}"))),
+        }
+    }
+    let _ = Ssh::retry(|| channel.wait_close());
+    match Ssh::retry(|| channel.exit_status()) {
+        Ok(0) => Ok(String::from_utf8_lossy(&output).to_string()),
+        Ok(status) => Err(SshError::Channel(format!("{cmd} exited with {status}"))),
+        Err(e) => Err(SshError::Channel(format!("Cannot run {cmd}: {e}"))),
+    }
+}
+
+pub fn open_sftp(session: &Session) -> Result<Sftp, SshError> {
+    match Ssh::retry(|| session.sftp()) {
+        Err(e) => Err(SshError::Sftp(format!("Cannot open sftp: {e}"))),
+        Ok(o) => Ok(o),
+    }
+}
+
+// like download, continuing a partial local file
+pub fn sftp_download(
+    session: &Session,
+    remotepath: &str,
+    localpath: &str,
+    progress: &mut Progress,
+    control: &Control,
+    options: ResumeOptions,
+) -> Result<(), SshError> {
+    println!("downloading: {remotepath}");
+    let sftp = open_sftp(session)?;
+    let path = Path::new(remotepath);
+    let source = match Ssh::retry(|| sftp.stat(path)) {
+        Err(e) => return Err(SshError::Sftp(format!("Cannot stat {remotepath}: {e}"))),
+        Ok(o) => remote_version(&o),
+    };
+    let recorded = read_local_record(localpath);
+    let offset = resume_offset(source, local_version(localpath).ok(), recorded);
+    let offset = checked_offset(session, offset, options, localpath, remotepath);
+    if offset > 0 {
+        println!("resuming {remotepath} at {offset}");
+    }
+    if offset < source.0 {
+        write_local_record(localpath, source)?;
+    }
+    let mut remote = match Ssh::retry(|| sftp.open(path)) {
+        Err(e) => {
+            return Err(SshError::Sftp(format!(
+                "Cannot open file {remotepath}: {e}"
+            )))
+        }
+        Ok(o) => o,
+    };
+    remote.seek(SeekFrom::Start(offset))?;
+    let mut local = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(offset == 0)
+        .open(localpath)?;
+    local.set_len(offset)?;
+    local.seek(SeekFrom::Start(offset))?;
+    let mut local = BufWriter::new(local);
+    let mut buffer = [0; 16000];
+    progress.start(source.0, offset);
+    loop {
+        control.proceed()?;
+        match remote.read(&mut buffer) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(time::Duration::from_millis(WAIT_MS));
+            }
+            Err(e) => return Err(SshError::Sftp(format!("Cannot read {remotepath}: {e}"))),
+            Ok(0) => break,
+            Ok(n) => {
+                local.write_all(&buffer[..n])?;
+                progress.add(n);
+            }
+        }
+    }
+    let local = local.into_inner().map_err(|e| e.into_error())?;
+    local.set_modified(UNIX_EPOCH + Duration::from_secs(source.1))?;
+    remove_local_record(localpath);
+    progress.finish();
+    println!("written: {}", progress.bytes());
+    Ok(())
+}
+
+// like upload, continuing a partial remote file
+pub fn sftp_upload(
+    session: &Session,
+    localpath: &str,
+    remotepath: &str,
+    progress: &mut Progress,
+    control: &Control,
+    options: ResumeOptions,
+) -> Result<(), SshError> {
+    println!("uploading: {localpath} to {remotepath}");
+    let source = local_version(localpath)?;
+    let sftp = open_sftp(session)?;
+    let path = Path::new(remotepath);
+    let partial = Ssh::retry(|| sftp.stat(path)).ok();
+    let recorded = read_remote_record(&sftp, remotepath);
+    let offset = resume_offset(source, partial.as_ref().map(remote_version), recorded);
+    let offset = checked_offset(session, offset, options, localpath, remotepath);
+    if offset > 0 {
+        println!("resuming {remotepath} at {offset}");
+    }
+    if offset < source.0 {
+        write_remote_record(&sftp, remotepath, source)?;
+    }
+    let mut flags = OpenFlags::WRITE | OpenFlags::CREATE;
+    if offset == 0 {
+        flags |= OpenFlags::TRUNCATE;
+    }
+    let mut remote = match Ssh::retry(|| sftp.open_mode(path, flags, 0o644, OpenType::File)) {
+        Err(e) => {
+            return Err(SshError::Sftp(format!(
+                "Cannot open file {remotepath}: {e}"
+            )))
+        }
+        Ok(o) => o,
+    };
+    remote.seek(SeekFrom::Start(offset))?;
+    let mut local = BufReader::new(File::open(localpath)?);
+    local.seek(SeekFrom::Start(offset))?;
+    let mut buffer = [0; 16000];
+    progress.start(source.0, offset);
+    loop {
+        control.proceed()?;
+        let n = local.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        let mut data = &buffer[..n];
+        while !data.is_empty() {
+            match remote.write(data) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(time::Duration::from_millis(WAIT_MS));
+                }
+                Err(e) => return Err(SshError::Sftp(format!("Cannot write {remotepath}: {e}"))),
+                Ok(n) => {
+                    data = &data[n..];
+                    progress.add(n);
+                }
+            }
+        }
+    }
+    if let Err(e) = Ssh::retry(|| remote.close()) {
+        return Err(SshError::Sftp(format!("Cannot close {remotepath}: {e}")));
+    }
+    let stat = FileStat {
+        size: None,
+        uid: None,
+        gid: None,
+        perm: None,
+        atime: Some(source.1),
+        mtime: Some(source.1),
+    };
+    if let Err(e) = Ssh::retry(|| sftp.setstat(path, stat.clone())) {
+        return Err(SshError::Sftp(format!(
+            "Cannot set mtime of {remotepath}: {e}"
+        )));
+    }
+    remove_remote_record(&sftp, remotepath);
+    progress.finish();
+    println!("written: {}", progress.bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn states(transfers: &Transfers) -> Vec<TransferState> {
+        transfers.list().iter().map(|t| t.state).collect()
+    }
+
+    #[test]
+    fn concurrency() {
+        let transfers = Transfers::default();
+        for i in 0..3 {
+            transfers.add(
+                1,
+                Direction::Upload,
+                &format!("/tmp/{i}"),
+                "/tmp",
+                TransferOptions::default(),
+            );
+        }
+        let jobs = transfers.next_jobs();
+        assert_eq!(jobs.len(), CONCURRENCY);
+        assert!(transfers.next_jobs().is_empty());
+        use TransferState::*;
+        assert_eq!(states(&transfers), vec![Running, Running, Queued]);
+
+        transfers.finish(&jobs[0], Ok(()));
+        let next = transfers.next_jobs();
+        assert_eq!(next.len(), 1);
+        assert_eq!(next[0].localpath(), "/tmp/2");
+        let e = SshError::Channel("closed".to_string());
+        let info = transfers.finish(&jobs[1], Err(e.clone()));
+        assert_eq!((info.state, info.error), (Failed, Some(e)));
+
+        transfers.clear();
+        assert_eq!(states(&transfers), vec![Running]);
+        transfers.set_concurrency(0);
+        transfers.add(
+            1,
+            Direction::Download,
+            "/tmp/3",
+            "/tmp/3",
+            TransferOptions::default(),
+        );
+        assert!(transfers.next_jobs().is_empty());
+    }
+
+    #[test]
+    fn pause_resume_cancel() {
+        use TransferState::*;
+        let transfers = Transfers::default();
+        let a = transfers
+            .add(
+                1,
+                Direction::Upload,
+                "/tmp/a",
+                "/tmp/a",
+                TransferOptions::default(),
+            )
+            .transfer;
+        let b = transfers
+            .add(
+                2,
+                Direction::Upload,
+                "/tmp/b",
+                "/tmp/b",
+                TransferOptions::default(),
+            )
+            .transfer;
+        let c = transfers
+            .add(
+                2,
+                Direction::Upload,
+                "/tmp/c",
+                "/tmp/c",
+                TransferOptions::default(),
+            )
+            .transfer;
+
+        // a queued job is skipped while paused
+        assert_eq!(transfers.pause(a).unwrap().state, Paused);
+        let jobs = transfers.next_jobs();
+        assert_eq!(
+            jobs.iter().map(|j| j.transfer()).collect::<Vec<_>>(),
+            vec![b, c]
+        );
+        assert_eq!(transfers.resume(a).unwrap().state, Queued);
+
+        assert_eq!(transfers.pause(b).unwrap().state, Paused);
+        assert!(jobs[0].control.pause.load(Ordering::SeqCst));
+        assert_eq!(transfers.resume(b).unwrap().state, Running);
+        assert!(jobs[0].control.proceed().is_ok());
+
+        // running jobs are finished by their thread
+        transfers.cancel_session(2);
+        assert_eq!(transfers.list()[2].state, Running);
+        let r = jobs[1].control.proceed();
+        assert!(matches!(r, Err(SshError::Cancelled(_))));
+        assert_eq!(transfers.finish(&jobs[1], r).state, Cancelled);
+
+        assert_eq!(transfers.cancel(a).unwrap().state, Cancelled);
+        assert!(transfers.pause(a).is_ok());
+        assert_eq!(states(&transfers), vec![Cancelled, Running, Cancelled]);
+        assert!(transfers.cancel(99).is_err());
+    }
+
+    #[test]
+    fn retry_failed() {
+        use TransferState::*;
+        let transfers = Transfers::default();
+        let resume = Some(ResumeOptions { checksum: true });
+        let options = TransferOptions {
+            resume,
+            symlinks: None,
+        };
+        let t = transfers.add(1, Direction::Download, "/tmp/d", "/tmp/d", options);
+        let job = transfers.next_jobs().remove(0);
+        transfers.cancel(t.transfer).unwrap();
+        let r = job.control.proceed();
+        transfers.finish(&job, r);
+        assert_eq!(transfers.retry(t.transfer).unwrap().state, Queued);
+        assert!(job.control.proceed().is_ok());
+        let job = transfers.next_jobs().remove(0);
+        let e = SshError::Sftp("Cannot read".to_string());
+        transfers.finish(&job, Err(e));
+        let info = transfers.retry(t.transfer).unwrap();
+        assert_eq!(
+            (info.state, info.error, info.resume),
+            (Queued, None, resume)
+        );
+        // only finished transfers are queued again
+        transfers.next_jobs();
+        assert_eq!(transfers.retry(t.transfer).unwrap().state, Running);
+    }
+
+    #[test]
+    fn resume_from() {
+        let source = (1000, 500);
+        assert_eq!(resume_offset(source, None, None), 0);
+        assert_eq!(resume_offset(source, Some((400, 600)), Some(source)), 400);
+        // the clock of the copy does not matter
+        assert_eq!(resume_offset(source, Some((400, 100)), Some(source)), 400);
+        assert_eq!(resume_offset(source, Some((1000, 500)), None), 1000);
+        // the source changed after the copy was started, or nothing recorded it
+        assert_eq!(resume_offset(source, Some((400, 600)), Some((900, 400))), 0);
+        assert_eq!(resume_offset(source, Some((400, 600)), None), 0);
+        assert_eq!(resume_offset(source, Some((1001, 600)), Some(source)), 0);
+    }
+
+    #[test]
+    fn source_record() {
+        assert_eq!(
+            record_path("/home/user/a.iso"),
+            "/home/user/.a.iso.xtauri-resume"
+        );
+        assert_eq!(
+            record_path(r"C:\data\a.iso"),
+            r"C:\data\.a.iso.xtauri-resume"
+        );
+        assert_eq!(record_path("a.iso"), ".a.iso.xtauri-resume");
+
+        let path = std::env::temp_dir().join("xtauri_record");
+        let path = path.to_string_lossy().to_string();
+        write_local_record(&path, (1000, 500)).unwrap();
+        assert_eq!(read_local_record(&path), Some((1000, 500)));
+        remove_local_record(&path);
+        assert!(!Path::new(&record_path(&path)).exists());
+        assert_eq!(read_local_record(&path), None);
+        assert_eq!(parse_record("xtauri-resume 12"), None);
+        assert_eq!(parse_record("1000 500"), None);
+
+        // a file of the user with that name is left alone
+        std::fs::write(record_path(&path), "mine").unwrap();
+        assert!(write_local_record(&path, (1000, 500)).is_err());
+        remove_local_record(&path);
+        assert_eq!(std::fs::read_to_string(record_path(&path)).unwrap(), "mine");
+        std::fs::remove_file(record_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn partial_checksum() {
+        let path = std::env::temp_dir().join("xtauri_partial_checksum");
+        std::fs::write(&path, b"abcdef").unwrap();
+        let path = path.to_string_lossy().to_string();
+        // echo -n abc | sha256sum
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(local_checksum(&path, 3).unwrap(), abc);
+        assert_ne!(local_checksum(&path, 6).unwrap(), abc);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
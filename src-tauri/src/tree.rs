@@ -0,0 +1,590 @@
+use ssh2::{FileStat, OpenFlags, OpenType, Session, Sftp};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use std::{thread, time};
+
+use super::command;
+use super::error::SshError;
+use super::progress::Progress;
+use super::ssh::Ssh;
+use super::transfer::{self, Control};
+
+const WAIT_MS: u64 = 20;
+
+// deeper trees are most likely a link loop
+const MAX_DEPTH: usize = 64;
+
+// what a directory transfer does with symlinks, follow copies what they point to
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkPolicy {
+    #[default]
+    Follow,
+    Link,
+    Skip,
+}
+
+// a file of the tree, path is relative to the root and separated by /
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    path: String,
+    kind: EntryKind,
+    mode: u32,
+    mtime: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum EntryKind {
+    Dir,
+    File(u64),
+    Link(String),
+}
+
+fn child(rel: &str, name: &str) -> String {
+    if rel.is_empty() {
+        name.to_string()
+    } else {
+        format!("{rel}/{name}")
+    }
+}
+
+fn remote_join(root: &str, rel: &str) -> String {
+    format!("{}/{rel}", root.trim_end_matches('/'))
+}
+
+fn is_link(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink())
+}
+
+// where an entry goes under root, refused when a directory on the way is a link,
+// made by this download or left by an earlier one, writing would follow it out of root
+fn local_path(root: &Path, rel: &str) -> Result<PathBuf, SshError> {
+    let mut path = root.to_path_buf();
+    let mut names = rel.split('/').peekable();
+    while let Some(name) = names.next() {
+        path.push(name);
+        if names.peek().is_some() && is_link(&path) {
+            let e = format!(
+                "Refusing to write {rel} through the link {}",
+                path.display()
+            );
+            return Err(SshError::Io(e));
+        }
+    }
+    Ok(path)
+}
+
+// an entry replaces a link at its path instead of following it
+fn remove_link(path: &Path) -> Result<(), SshError> {
+    if is_link(path) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+// the local tree under dir, directories come before what they contain
+fn local_tree(
+    dir: &Path,
+    rel: &str,
+    symlinks: SymlinkPolicy,
+    parents: &mut Vec<std::path::PathBuf>,
+    entries: &mut Vec<Entry>,
+) -> Result<(), SshError> {
+    // a followed link to a parent would never end
+    let real = fs::canonicalize(dir)?;
+    if parents.contains(&real) || parents.len() > MAX_DEPTH {
+        println!("skipping {}: link loop", dir.display());
+        return Ok(());
+    }
+    parents.push(real);
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        names.push(entry?.file_name());
+    }
+    names.sort();
+    for name in names {
+        let path = dir.join(&name);
+        let rel = child(rel, &name.to_string_lossy());
+        let mut meta = fs::symlink_metadata(&path)?;
+        if meta.file_type().is_symlink() {
+            match symlinks {
+                SymlinkPolicy::Skip => continue,
+                SymlinkPolicy::Link => {
+                    let target = fs::read_link(&path)?.to_string_lossy().to_string();
+                    entries.push(Entry {
+                        path: rel,
+                        kind: EntryKind::Link(target),
+                        mode: 0o777,
+                        mtime: local_mtime(&meta),
+                    });
+                    continue;
+                }
+                SymlinkPolicy::Follow => match fs::metadata(&path) {
+                    Err(e) => {
+                        println!("skipping {}: {e}", path.display());
+                        continue;
+                    }
+                    Ok(o) => meta = o,
+                },
+            }
+        }
+        let kind = if meta.is_dir() {
+            EntryKind::Dir
+        } else if meta.is_file() {
+            EntryKind::File(meta.len())
+        } else {
+            println!("skipping {}: not a file", path.display());
+            continue;
+        };
+        entries.push(Entry {
+            path: rel.clone(),
+            kind: kind.clone(),
+            mode: local_mode(&meta),
+            mtime: local_mtime(&meta),
+        });
+        if kind == EntryKind::Dir {
+            local_tree(&path, &rel, symlinks, parents, entries)?;
+        }
+    }
+    parents.pop();
+    Ok(())
+}
+
+// the remote tree under dir, like local_tree
+fn remote_tree(
+    sftp: &Sftp,
+    dir: &str,
+    rel: &str,
+    symlinks: SymlinkPolicy,
+    parents: &mut Vec<std::path::PathBuf>,
+    entries: &mut Vec<Entry>,
+) -> Result<(), SshError> {
+    let real = match Ssh::retry(|| sftp.realpath(Path::new(dir))) {
+        Err(e) => return Err(SshError::Sftp(format!("Cannot read real path {dir}: {e}"))),
+        Ok(o) => o,
+    };
+    if parents.contains(&real) || parents.len() > MAX_DEPTH {
+        println!("skipping {dir}: link loop");
+        return Ok(());
+    }
+    parents.push(real);
+    let mut files = match Ssh::retry(|| sftp.readdir(Path::new(dir))) {
+        Err(e) => return Err(SshError::Sftp(format!("Cannot read directory {dir}: {e}"))),
+        Ok(o) => o,
+    };
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    for (path, mut stat) in files {
+        let name = match path.file_name() {
+            None => continue,
+            Some(o) => o.to_string_lossy().to_string(),
+        };
+        let path = remote_join(dir, &name);
+        let rel = child(rel, &name);
+        if stat.file_type().is_symlink() {
+            match symlinks {
+                SymlinkPolicy::Skip => continue,
+                SymlinkPolicy::Link => {
+                    let target = match Ssh::retry(|| sftp.readlink(Path::new(&path))) {
+                        Err(e) => {
+                            return Err(SshError::Sftp(format!("Cannot read link {path}: {e}")))
+                        }
+                        Ok(o) => o.to_string_lossy().to_string(),
+                    };
+                    entries.push(Entry {
+                        path: rel,
+                        kind: EntryKind::Link(target),
+                        mode: 0o777,
+                        mtime: stat.mtime.unwrap_or(0),
+                    });
+                    continue;
+                }
+                SymlinkPolicy::Follow => match Ssh::retry(|| sftp.stat(Path::new(&path))) {
+                    Err(e) => {
+                        println!("skipping {path}: {e}");
+                        continue;
+                    }
+                    Ok(o) => stat = o,
+                },
+            }
+        }
+        let kind = if stat.is_dir() {
+            EntryKind::Dir
+        } else if stat.is_file() {
+            EntryKind::File(stat.size.unwrap_or(0))
+        } else {
+            println!("skipping {path}: not a file");
+            continue;
+        };
+        entries.push(Entry {
+            path: rel.clone(),
+            kind: kind.clone(),
+            mode: stat.perm.unwrap_or(0) & 0o7777,
+            mtime: stat.mtime.unwrap_or(0),
+        });
+        if kind == EntryKind::Dir {
+            remote_tree(sftp, &path, &rel, symlinks, parents, entries)?;
+        }
+    }
+    parents.pop();
+    Ok(())
+}
+
+#[cfg(unix)]
+fn local_mode(meta: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn local_mode(meta: &fs::Metadata) -> u32 {
+    let mode = if meta.is_dir() { 0o755 } else { 0o644 };
+    if meta.permissions().readonly() {
+        mode & 0o555
+    } else {
+        mode
+    }
+}
+
+fn local_mtime(meta: &fs::Metadata) -> u64 {
+    match meta.modified().map(|m| m.duration_since(UNIX_EPOCH)) {
+        Ok(Ok(o)) => o.as_secs(),
+        _ => 0,
+    }
+}
+
+// the aggregate size and file count of the tree
+fn start(progress: &mut Progress, entries: &[Entry]) {
+    let mut files = 0;
+    let mut total = 0;
+    for entry in entries {
+        if let EntryKind::File(size) = entry.kind {
+            files += 1;
+            total += size;
+        }
+    }
+    progress.set_files(files);
+    progress.start(total, 0);
+}
+
+// copy to the end of from, both sides may be non-blocking sftp files
+fn copy(
+    from: &mut impl Read,
+    to: &mut impl Write,
+    progress: &mut Progress,
+    control: &Control,
+) -> Result<(), SshError> {
+    let mut buffer = [0; 16000];
+    loop {
+        control.proceed()?;
+        let n = match from.read(&mut buffer) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(time::Duration::from_millis(WAIT_MS));
+                continue;
+            }
+            Err(e) => return Err(SshError::Io(format!("Cannot read: {e}"))),
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+        };
+        let mut data = &buffer[..n];
+        while !data.is_empty() {
+            match to.write(data) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(time::Duration::from_millis(WAIT_MS));
+                }
+                Err(e) => return Err(SshError::Io(format!("Cannot write: {e}"))),
+                Ok(n) => {
+                    data = &data[n..];
+                    progress.add(n);
+                }
+            }
+        }
+    }
+}
+
+fn remote_mkdir(sftp: &Sftp, path: &str) -> Result<(), SshError> {
+    if let Ok(stat) = Ssh::retry(|| sftp.stat(Path::new(path))) {
+        if stat.is_dir() {
+            return Ok(());
+        }
+    }
+    // writable until its own mode is set at the end
+    match Ssh::retry(|| sftp.mkdir(Path::new(path), 0o700)) {
+        Err(e) => Err(SshError::Sftp(format!("Cannot make dir {path}: {e}"))),
+        Ok(_) => Ok(()),
+    }
+}
+
+fn set_remote_stat(sftp: &Sftp, path: &str, mode: u32, mtime: u64) -> Result<(), SshError> {
+    let stat = FileStat {
+        size: None,
+        uid: None,
+        gid: None,
+        perm: Some(mode),
+        atime: Some(mtime),
+        mtime: Some(mtime),
+    };
+    match Ssh::retry(|| sftp.setstat(Path::new(path), stat.clone())) {
+        Err(e) => Err(SshError::Sftp(format!("Cannot set mode of {path}: {e}"))),
+        Ok(_) => Ok(()),
+    }
+}
+
+// the mtime first, a read-only mode would prevent it
+fn set_local_stat(path: &Path, mode: u32, mtime: u64) -> Result<(), SshError> {
+    let mtime = UNIX_EPOCH + Duration::from_secs(mtime);
+    // directories open read-only on unix, windows needs a writable file
+    if File::open(path)
+        .and_then(|f| f.set_modified(mtime))
+        .is_err()
+    {
+        File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(mtime)?;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    {
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_readonly(mode & 0o222 == 0);
+        fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+// copy the local directory localpath into remotepath, created when missing,
+// files keep their mode and mtime
+pub fn upload(
+    session: &Session,
+    localpath: &str,
+    remotepath: &str,
+    progress: &mut Progress,
+    control: &Control,
+    symlinks: SymlinkPolicy,
+) -> Result<(), SshError> {
+    println!("uploading directory: {localpath} to {remotepath}");
+    let root = Path::new(localpath);
+    let meta = fs::metadata(root)?;
+    if !meta.is_dir() {
+        return Err(SshError::Io(format!("{localpath} is not a directory")));
+    }
+    let mut entries = Vec::new();
+    local_tree(root, "", symlinks, &mut Vec::new(), &mut entries)?;
+    let sftp = transfer::open_sftp(session)?;
+    start(progress, &entries);
+    remote_mkdir(&sftp, remotepath)?;
+    for entry in &entries {
+        let path = remote_join(remotepath, &entry.path);
+        match &entry.kind {
+            EntryKind::Dir => remote_mkdir(&sftp, &path)?,
+            EntryKind::File(_) => {
+                let mut from = File::open(root.join(&entry.path))?;
+                let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+                let open =
+                    Ssh::retry(|| sftp.open_mode(Path::new(&path), flags, 0o600, OpenType::File));
+                let mut to = match open {
+                    Err(e) => {
+                        return Err(SshError::Sftp(format!("Cannot create file {path}: {e}")))
+                    }
+                    Ok(o) => o,
+                };
+                copy(&mut from, &mut to, progress, control)
+                    .map_err(|e| e.map_message(|m| format!("{path}: {m}")))?;
+                if let Err(e) = Ssh::retry(|| to.close()) {
+                    return Err(SshError::Sftp(format!("Cannot close {path}: {e}")));
+                }
+                set_remote_stat(&sftp, &path, entry.mode, entry.mtime)?;
+                progress.file_done();
+            }
+            EntryKind::Link(target) => {
+                let cmd = format!(
+                    "ln -sfn -- {} {}",
+                    command::quote(target),
+                    command::quote(&path)
+                );
+                transfer::exec(session, &cmd)?;
+            }
+        }
+    }
+    // directories last, their files changed their mtime
+    for entry in entries.iter().rev().filter(|e| e.kind == EntryKind::Dir) {
+        let path = remote_join(remotepath, &entry.path);
+        set_remote_stat(&sftp, &path, entry.mode, entry.mtime)?;
+    }
+    set_remote_stat(&sftp, remotepath, local_mode(&meta), local_mtime(&meta))?;
+    progress.finish();
+    println!("uploaded {} files", entries.len());
+    Ok(())
+}
+
+// copy the remote directory remotepath into localpath, like upload
+pub fn download(
+    session: &Session,
+    remotepath: &str,
+    localpath: &str,
+    progress: &mut Progress,
+    control: &Control,
+    symlinks: SymlinkPolicy,
+) -> Result<(), SshError> {
+    println!("downloading directory: {remotepath} to {localpath}");
+    let sftp = transfer::open_sftp(session)?;
+    let stat = match Ssh::retry(|| sftp.stat(Path::new(remotepath))) {
+        Err(e) => return Err(SshError::Sftp(format!("Cannot stat {remotepath}: {e}"))),
+        Ok(o) => o,
+    };
+    if !stat.is_dir() {
+        return Err(SshError::Sftp(format!("{remotepath} is not a directory")));
+    }
+    let mut entries = Vec::new();
+    remote_tree(
+        &sftp,
+        remotepath,
+        "",
+        symlinks,
+        &mut Vec::new(),
+        &mut entries,
+    )?;
+    start(progress, &entries);
+    let root = Path::new(localpath);
+    fs::create_dir_all(root)?;
+    for entry in &entries {
+        let path = local_path(root, &entry.path)?;
+        match &entry.kind {
+            EntryKind::Dir => {
+                remove_link(&path)?;
+                fs::create_dir_all(&path)?;
+            }
+            EntryKind::File(_) => {
+                let remote = remote_join(remotepath, &entry.path);
+                let mut from = match Ssh::retry(|| sftp.open(Path::new(&remote))) {
+                    Err(e) => {
+                        return Err(SshError::Sftp(format!("Cannot open file {remote}: {e}")))
+                    }
+                    Ok(o) => o,
+                };
+                remove_link(&path)?;
+                let mut to = File::create(&path)?;
+                copy(&mut from, &mut to, progress, control)
+                    .map_err(|e| e.map_message(|m| format!("{remote}: {m}")))?;
+                drop(to);
+                set_local_stat(&path, entry.mode, entry.mtime)?;
+                progress.file_done();
+            }
+            EntryKind::Link(target) => {
+                match fs::symlink_metadata(&path) {
+                    Ok(meta) if meta.is_dir() => {
+                        let e = format!(
+                            "Cannot replace the directory {} with a link",
+                            path.display()
+                        );
+                        return Err(SshError::Io(e));
+                    }
+                    Ok(_) => fs::remove_file(&path)?,
+                    Err(_) => (),
+                }
+                #[cfg(unix)]
+                std::os::unix::fs::symlink(target, &path)?;
+                #[cfg(not(unix))]
+                println!("skipping link {} to {target}", path.display());
+            }
+        }
+    }
+    // directories last, windows may not set their mtime
+    for entry in entries.iter().rev().filter(|e| e.kind == EntryKind::Dir) {
+        if let Err(e) = set_local_stat(&root.join(&entry.path), entry.mode, entry.mtime) {
+            println!("{}: {e}", entry.path);
+        }
+    }
+    let (mode, mtime) = (stat.perm.unwrap_or(0o755) & 0o7777, stat.mtime.unwrap_or(0));
+    if let Err(e) = set_local_stat(root, mode, mtime) {
+        println!("{localpath}: {e}");
+    }
+    progress.finish();
+    println!("downloaded {} files", entries.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joined_paths() {
+        assert_eq!(child("", "a"), "a");
+        assert_eq!(child("a", "b"), "a/b");
+        assert_eq!(remote_join("/", "a/b"), "/a/b");
+        assert_eq!(remote_join("/home/user/", "a"), "/home/user/a");
+        assert_eq!(remote_join("dir", "a"), "dir/a");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn paths_through_links() {
+        use std::os::unix::fs::symlink;
+        let root = std::env::temp_dir().join("xtauri_tree_links");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("dir")).unwrap();
+        symlink(std::env::temp_dir(), root.join("link")).unwrap();
+        symlink(std::env::temp_dir(), root.join("dir/link")).unwrap();
+
+        assert_eq!(
+            local_path(&root, "dir/file").unwrap(),
+            root.join("dir/file")
+        );
+        // the link itself is replaced, not written through
+        assert_eq!(local_path(&root, "link").unwrap(), root.join("link"));
+        assert!(local_path(&root, "link/file").is_err());
+        assert!(local_path(&root, "dir/link/sub/file").is_err());
+
+        remove_link(&root.join("link")).unwrap();
+        remove_link(&root.join("dir")).unwrap();
+        assert!(fs::symlink_metadata(root.join("link")).is_err());
+        assert!(root.join("dir").is_dir());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_policies() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+        let root = std::env::temp_dir().join("xtauri_tree");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/file"), b"hello").unwrap();
+        fs::set_permissions(root.join("sub/file"), fs::Permissions::from_mode(0o640)).unwrap();
+        symlink("sub/file", root.join("link")).unwrap();
+        // a loop when followed
+        symlink("..", root.join("sub/up")).unwrap();
+
+        let tree = |symlinks| {
+            let mut entries = Vec::new();
+            local_tree(&root, "", symlinks, &mut Vec::new(), &mut entries).unwrap();
+            entries
+        };
+        let paths = |entries: &[Entry]| entries.iter().map(|e| e.path.clone()).collect::<Vec<_>>();
+
+        let entries = tree(SymlinkPolicy::Skip);
+        assert_eq!(paths(&entries), vec!["sub", "sub/file"]);
+        assert_eq!(entries[1].kind, EntryKind::File(5));
+        assert_eq!(entries[1].mode, 0o640);
+        assert!(entries[1].mtime > 0);
+
+        let entries = tree(SymlinkPolicy::Link);
+        assert_eq!(paths(&entries), vec!["link", "sub", "sub/file", "sub/up"]);
+        assert_eq!(entries[0].kind, EntryKind::Link("sub/file".to_string()));
+        assert_eq!(entries[3].kind, EntryKind::Link("..".to_string()));
+
+        // the loop stops at the directory it leads back to
+        let entries = tree(SymlinkPolicy::Follow);
+        assert_eq!(paths(&entries), vec!["link", "sub", "sub/file", "sub/up"]);
+        assert_eq!(entries[0].kind, EntryKind::File(5));
+        assert_eq!(entries[3].kind, EntryKind::Dir);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
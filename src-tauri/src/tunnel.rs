@@ -0,0 +1,170 @@
+use mio::{Events, Interest, Poll, Token};
+use ssh2::Channel;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use std::{thread, time};
+
+// poll timeout, libssh2 may buffer channel data without the socket being readable
+const WAIT_MS: u64 = 20;
+
+// bytes moved by pumps, shared with the threads, stop ends them
+#[derive(Default)]
+pub struct Traffic {
+    pub sent: AtomicU64,
+    pub received: AtomicU64,
+    pub stop: AtomicBool,
+}
+
+// two connected loopback sockets, an ssh session can run on one end
+// while the other end is pumped to a channel or a command, another local
+// process may connect to the listener first, its connection is dropped
+pub fn local_pair() -> std::io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let client = TcpStream::connect(listener.local_addr()?)?;
+    let server = accept_from(&listener, &client)?;
+    Ok((client, server))
+}
+
+// the connection of client, others are dropped
+fn accept_from(listener: &TcpListener, client: &TcpStream) -> std::io::Result<TcpStream> {
+    loop {
+        let (server, peer) = listener.accept()?;
+        if peer == client.local_addr()? {
+            return Ok(server);
+        }
+        println!("dropping unexpected connection from {peer}");
+    }
+}
+
+pub fn write_all_nonblocking(w: &mut impl Write, mut data: &[u8]) -> std::io::Result<()> {
+    while !data.is_empty() {
+        match w.write(data) {
+            Ok(0) => return Err(std::io::Error::from(ErrorKind::WriteZero)),
+            Ok(n) => data = &data[n..],
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(time::Duration::from_millis(1));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// copy bytes both ways between a local socket and a channel of a non-blocking
+// session until the channel is closed, session_tcp is the session socket
+pub fn pump(
+    mut channel: Channel,
+    mut local: TcpStream,
+    session_tcp: TcpStream,
+    traffic: &Traffic,
+) -> Result<(), String> {
+    local.set_nonblocking(true).map_err(|e| e.to_string())?;
+    session_tcp
+        .set_nonblocking(true)
+        .map_err(|e| e.to_string())?;
+    let mut mio_local =
+        mio::net::TcpStream::from_std(local.try_clone().map_err(|e| e.to_string())?);
+    let mut mio_session = mio::net::TcpStream::from_std(session_tcp);
+
+    let mut poller = Poll::new().map_err(|e| e.to_string())?;
+    poller
+        .registry()
+        .register(&mut mio_local, Token(0), Interest::READABLE)
+        .map_err(|e| e.to_string())?;
+    poller
+        .registry()
+        .register(&mut mio_session, Token(1), Interest::READABLE)
+        .map_err(|e| e.to_string())?;
+    let mut events = Events::with_capacity(16);
+    let mut buf = vec![0; 32 * 1024];
+    let mut local_open = true;
+
+    let result = 'pump: loop {
+        if traffic.stop.load(Ordering::SeqCst) {
+            break Ok(());
+        }
+        if let Err(e) = poller.poll(&mut events, Some(Duration::from_millis(WAIT_MS))) {
+            if e.kind() != ErrorKind::Interrupted {
+                break Err(format!("Poll error: {e}"));
+            }
+        }
+
+        // local -> channel
+        while local_open {
+            match local.read(&mut buf) {
+                Ok(0) => {
+                    local_open = false;
+                    let _ = channel.send_eof();
+                }
+                Ok(n) => {
+                    if let Err(e) = write_all_nonblocking(&mut channel, &buf[..n]) {
+                        break 'pump Err(format!("Channel write error: {e}"));
+                    }
+                    traffic.sent.fetch_add(n as u64, Ordering::SeqCst);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => break 'pump Err(format!("Local read error: {e}")),
+            }
+        }
+
+        // channel -> local
+        loop {
+            match channel.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if let Err(e) = write_all_nonblocking(&mut local, &buf[..n]) {
+                        break 'pump Err(format!("Local write error: {e}"));
+                    }
+                    traffic.received.fetch_add(n as u64, Ordering::SeqCst);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => break 'pump Err(format!("Channel read error: {e}")),
+            }
+        }
+
+        if channel.eof() {
+            break Ok(());
+        }
+
+        // this must be done in windows
+        let _ = poller
+            .registry()
+            .reregister(&mut mio_local, Token(0), Interest::READABLE);
+        let _ = poller
+            .registry()
+            .reregister(&mut mio_session, Token(1), Interest::READABLE);
+    };
+
+    let _ = local.shutdown(Shutdown::Both);
+    let _ = channel.close();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_pair_connected() {
+        let (mut a, mut b) = local_pair().unwrap();
+        a.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        b.write_all(b"pong").unwrap();
+        a.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[test]
+    fn other_connection_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let other = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = accept_from(&listener, &client).unwrap();
+        assert_eq!(server.peer_addr().unwrap(), client.local_addr().unwrap());
+        assert_ne!(server.peer_addr().unwrap(), other.local_addr().unwrap());
+    }
+}
@@ -0,0 +1,344 @@
+use libssh2_sys as raw;
+use ssh2::Session;
+use std::collections::hash_map::RandomState;
+use std::ffi::{c_char, c_int};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::{thread, time};
+
+use super::command;
+use super::incoming::{self, RawChannel, CALLBACK_X11};
+
+const WAIT_MS: u64 = 20;
+const X11_TIMEOUT_SECS: u64 = 10;
+const AUTH_PROTOCOL: &str = "MIT-MAGIC-COOKIE-1";
+
+extern "C" {
+    fn libssh2_channel_x11_req_ex(
+        channel: *mut raw::LIBSSH2_CHANNEL,
+        single_connection: c_int,
+        auth_proto: *const c_char,
+        auth_cookie: *const c_char,
+        screen_number: c_int,
+    ) -> c_int;
+}
+
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Display {
+    Unix(PathBuf),
+    Tcp(String, u16),
+}
+
+// ssh -X, every terminal channel asks for x11 forwarding and the server
+// sets its DISPLAY, the clients come back as x11 channels
+pub struct X11 {
+    fake: Vec<u8>,
+    stop: Arc<AtomicBool>,
+}
+
+impl X11 {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+    // on a session channel before its shell starts
+    pub fn request(&self, channel: &RawChannel) -> Result<(), String> {
+        let proto = incoming::c_string(AUTH_PROTOCOL);
+        let fake_hex = incoming::c_string(&to_hex(&self.fake));
+        let r = channel.retry(|c| unsafe {
+            libssh2_channel_x11_req_ex(c, 0, proto.as_ptr(), fake_hex.as_ptr(), 0) as isize
+        });
+        if r != 0 {
+            return Err(format!("X11 forwarding request refused: error {r}"));
+        }
+        Ok(())
+    }
+}
+
+// the remote side gets a random cookie, replaced with the real one from
+// xauth when each connection is set up, like openssh does
+pub fn start(session: &Session) -> Result<X11, String> {
+    let local_display = match std::env::var("DISPLAY") {
+        Err(_) => return Err("X11 forwarding: DISPLAY is not set".to_string()),
+        Ok(o) => o,
+    };
+    let address = parse_display(&local_display)?;
+    let cookie = local_cookie(&local_display);
+    let fake = fake_cookie();
+
+    incoming::listen(session, CALLBACK_X11);
+    println!("x11 forwarding to {local_display}");
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let x11 = X11 {
+        fake: fake.clone(),
+        stop: stop.clone(),
+    };
+    let session = session.clone();
+    thread::spawn(move || {
+        while !stop.load(Ordering::SeqCst) {
+            for channel in incoming::accept(&session, CALLBACK_X11) {
+                let (address, stop) = (address.clone(), stop.clone());
+                let (fake, cookie) = (fake.clone(), cookie.clone());
+                thread::spawn(move || {
+                    if let Err(e) = bridge(channel, &address, &fake, cookie.as_deref(), &stop) {
+                        println!("x11 connection closed: {e}");
+                    }
+                });
+            }
+            thread::sleep(time::Duration::from_millis(WAIT_MS));
+        }
+        println!("x11 forwarding stopped");
+    });
+    Ok(x11)
+}
+
+fn bridge(
+    channel: RawChannel,
+    address: &Display,
+    fake: &[u8],
+    cookie: Option<&[u8]>,
+    stop: &AtomicBool,
+) -> Result<(), String> {
+    // the connection setup carries the cookie
+    let mut header = [0; 12];
+    read_exact(&channel, &mut header)?;
+    let mut setup = header.to_vec();
+    setup.resize(setup_len(&header)?, 0);
+    read_exact(&channel, &mut setup[12..])?;
+    let setup = rewrite_setup(&setup, fake, cookie)?;
+
+    let mut local = connect(address)?;
+    local.write_all(&setup).map_err(|e| e.to_string())?;
+    incoming::pump(channel, local, stop)
+}
+
+fn read_exact(channel: &RawChannel, buf: &mut [u8]) -> Result<(), String> {
+    let start = time::Instant::now();
+    let mut done = 0;
+    while done < buf.len() {
+        if start.elapsed().as_secs() > X11_TIMEOUT_SECS {
+            return Err("X11 connection setup timeout".to_string());
+        }
+        match channel.read(&mut buf[done..]) {
+            Ok(0) if channel.eof() => return Err("X11 connection closed".to_string()),
+            Ok(n) => done += n,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(time::Duration::from_millis(WAIT_MS));
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    Ok(())
+}
+
+fn connect(address: &Display) -> Result<Box<dyn Stream>, String> {
+    match address {
+        #[cfg(unix)]
+        Display::Unix(path) => {
+            let stream = std::os::unix::net::UnixStream::connect(path)
+                .map_err(|e| format!("Cannot connect to X server {}: {e}", path.display()))?;
+            stream.set_nonblocking(true).map_err(|e| e.to_string())?;
+            Ok(Box::new(stream))
+        }
+        #[cfg(not(unix))]
+        Display::Unix(path) => Err(format!("Unix display {} not supported", path.display())),
+        Display::Tcp(host, port) => {
+            let stream = TcpStream::connect((host.as_str(), *port))
+                .map_err(|e| format!("Cannot connect to X server {host}:{port}: {e}"))?;
+            stream.set_nonblocking(true).map_err(|e| e.to_string())?;
+            Ok(Box::new(stream))
+        }
+    }
+}
+
+// :0, unix:0.0 and /path/socket:0 are unix sockets, host:0 is tcp port 6000
+fn parse_display(display: &str) -> Result<Display, String> {
+    let invalid = || format!("Invalid DISPLAY: {display}");
+    if display.starts_with('/') {
+        return Ok(Display::Unix(PathBuf::from(display)));
+    }
+    let (host, number) = display.rsplit_once(':').ok_or_else(invalid)?;
+    let number = number.split('.').next().unwrap_or_default();
+    let number: u16 = number.parse().map_err(|_| invalid())?;
+    if host.is_empty() || host == "unix" {
+        let path = PathBuf::from(format!("/tmp/.X11-unix/X{number}"));
+        return Ok(Display::Unix(path));
+    }
+    Ok(Display::Tcp(host.to_string(), 6000 + number))
+}
+
+fn local_cookie(display: &str) -> Option<Vec<u8>> {
+    let (stdout, stderr, _) = command::run(&format!("xauth list {display}"));
+    if !stderr.is_empty() {
+        println!("xauth: {stderr}");
+    }
+    parse_xauth(&stdout)
+}
+
+// "host/unix:0  MIT-MAGIC-COOKIE-1  0123abcd..."
+fn parse_xauth(output: &str) -> Option<Vec<u8>> {
+    output.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [_, proto, cookie] if *proto == AUTH_PROTOCOL => from_hex(cookie),
+            _ => None,
+        }
+    })
+}
+
+fn fake_cookie() -> Vec<u8> {
+    // hashers are keyed from the os random source
+    let mut cookie = Vec::new();
+    for _ in 0..2 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            time::SystemTime::UNIX_EPOCH
+                .elapsed()
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        cookie.extend(hasher.finish().to_be_bytes());
+    }
+    cookie
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+fn pad4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+fn read_u16(header: &[u8], at: usize) -> u16 {
+    let bytes = [header[at], header[at + 1]];
+    match header[0] {
+        b'B' => u16::from_be_bytes(bytes),
+        _ => u16::from_le_bytes(bytes),
+    }
+}
+
+fn write_u16(header: &mut [u8], at: usize, value: u16) {
+    let bytes = match header[0] {
+        b'B' => value.to_be_bytes(),
+        _ => value.to_le_bytes(),
+    };
+    header[at..at + 2].copy_from_slice(&bytes);
+}
+
+// size of the x11 connection setup request, header, auth name and data
+fn setup_len(header: &[u8]) -> Result<usize, String> {
+    if header[0] != b'B' && header[0] != b'l' {
+        return Err("Invalid X11 connection setup".to_string());
+    }
+    let name = read_u16(header, 6) as usize;
+    let data = read_u16(header, 8) as usize;
+    Ok(12 + pad4(name) + pad4(data))
+}
+
+// check the fake cookie and put the real one, or no auth at all when the
+// local display has none
+fn rewrite_setup(setup: &[u8], fake: &[u8], cookie: Option<&[u8]>) -> Result<Vec<u8>, String> {
+    let name_len = read_u16(setup, 6) as usize;
+    let data_len = read_u16(setup, 8) as usize;
+    let name = &setup[12..12 + name_len];
+    let data_start = 12 + pad4(name_len);
+    let data = &setup[data_start..data_start + data_len];
+    if name != AUTH_PROTOCOL.as_bytes() || data != fake {
+        return Err("X11 connection rejected, wrong authentication".to_string());
+    }
+
+    let mut header = setup[..12].to_vec();
+    let (name, data) = match cookie {
+        None => (&[][..], &[][..]),
+        Some(cookie) => (AUTH_PROTOCOL.as_bytes(), cookie),
+    };
+    write_u16(&mut header, 6, name.len() as u16);
+    write_u16(&mut header, 8, data.len() as u16);
+    let mut rewritten = header;
+    rewritten.extend(name);
+    rewritten.resize(12 + pad4(name.len()), 0);
+    rewritten.extend(data);
+    rewritten.resize(12 + pad4(name.len()) + pad4(data.len()), 0);
+    Ok(rewritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(order: u8, name: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut s = vec![order, 0, 0, 11, 0, 0, 0, 0, 0, 0, 0, 0];
+        write_u16(&mut s, 2, 11);
+        write_u16(&mut s, 6, name.len() as u16);
+        write_u16(&mut s, 8, data.len() as u16);
+        s.extend(name);
+        s.resize(12 + pad4(name.len()), 0);
+        s.extend(data);
+        s.resize(12 + pad4(name.len()) + pad4(data.len()), 0);
+        s
+    }
+
+    #[test]
+    fn display() {
+        let unix = |p: &str| Display::Unix(PathBuf::from(p));
+        assert_eq!(parse_display(":0").unwrap(), unix("/tmp/.X11-unix/X0"));
+        assert_eq!(
+            parse_display("unix:1.0").unwrap(),
+            unix("/tmp/.X11-unix/X1")
+        );
+        assert_eq!(
+            parse_display("localhost:10.0").unwrap(),
+            Display::Tcp("localhost".to_string(), 6010)
+        );
+        let xquartz = "/private/tmp/com.apple.launchd.abc/org.xquartz:0";
+        assert_eq!(parse_display(xquartz).unwrap(), unix(xquartz));
+        assert!(parse_display("nodisplay").is_err());
+    }
+
+    #[test]
+    fn xauth() {
+        let output = "myhost/unix:0  MIT-MAGIC-COOKIE-1  00ff10ab\n";
+        assert_eq!(parse_xauth(output), Some(vec![0, 0xff, 0x10, 0xab]));
+        assert_eq!(parse_xauth(""), None);
+        assert_eq!(
+            parse_xauth("myhost/unix:0  XDM-AUTHORIZATION-1  00ff"),
+            None
+        );
+        assert_eq!(from_hex(&to_hex(&fake_cookie())).unwrap().len(), 16);
+    }
+
+    #[test]
+    fn setup_cookie() {
+        let fake = fake_cookie();
+        let real = [7; 16];
+        for order in [b'B', b'l'] {
+            let s = setup(order, AUTH_PROTOCOL.as_bytes(), &fake);
+            assert_eq!(setup_len(&s[..12]).unwrap(), s.len());
+            let r = rewrite_setup(&s, &fake, Some(&real)).unwrap();
+            assert_eq!(r, setup(order, AUTH_PROTOCOL.as_bytes(), &real));
+            let r = rewrite_setup(&s, &fake, None).unwrap();
+            assert_eq!(r, setup(order, b"", b""));
+        }
+        let s = setup(b'l', AUTH_PROTOCOL.as_bytes(), &[1; 16]);
+        assert!(rewrite_setup(&s, &fake, Some(&real)).is_err());
+        assert!(setup_len(b"x00000000000").is_err());
+    }
+}
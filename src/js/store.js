@@ -17,9 +17,12 @@ export const Error = writable("");
 export const UserStore = writable({
   user: "",
   server: "",
+  sessionId: 0,
   isConnected: false,
   isConnecting: false,
   needPassword: false,
 });
 export const Progress = writable(0);
+// queued transfers by id, updated by transfer-updated events
+export const TransferStore = writable({});
 export const Property = writable({key: 'value'});